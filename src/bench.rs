@@ -0,0 +1,159 @@
+//! Benchmark of the compression throughput at different thread counts
+
+use crate::{
+    cli::{BenchOptions, ProgressFormat},
+    progress::Progress,
+};
+use anyhow::{anyhow, Context, Result};
+use comva::*;
+use globset::GlobSet;
+use indicatif::MultiProgress;
+use magick_rust::MagickWand;
+use std::{env, fs, path::Path, process, thread, time::Instant};
+
+/// Size of the generated sample images
+const SAMPLE_SIZE: (usize, usize) = (1920, 1080);
+
+pub fn run(bench: &BenchOptions, multi: &MultiProgress) -> Result<()> {
+    init_imagemagick();
+    let work_dir = env::temp_dir().join(format!("comva-bench-{}", process::id()));
+    let result = run_in(bench, multi, &work_dir);
+    // The generated samples and outputs are never kept
+    fs::remove_dir_all(&work_dir).unwrap_or_default();
+    result
+}
+
+fn run_in(bench: &BenchOptions, multi: &MultiProgress, work_dir: &Path) -> Result<()> {
+    let samples_dir = match &bench.path {
+        Some(path) => path
+            .canonicalize()
+            .with_context(|| format!("Failed to find directory {path:?}."))?,
+        None => {
+            let samples_dir = work_dir.join("samples");
+            generate_samples(&samples_dir, bench.samples)?;
+            samples_dir
+        }
+    };
+    let settings = IndexSettings {
+        max_depth: None,
+        exclude: GlobSet::empty(),
+        detect_content: false,
+        skip_dirs: Vec::new(),
+        follow_symlinks: false,
+        ignore_hidden: true,
+        since: None,
+        options: None,
+    };
+    let files = index(&samples_dir, &settings)?;
+    if files.is_empty() {
+        return Err(anyhow!("No media files found in {samples_dir:?}."));
+    }
+    let total_size: u64 = files
+        .iter()
+        .filter_map(|file| fs::metadata(&file.path).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+    let thread_counts = match bench.threads.is_empty() {
+        true => default_thread_counts(),
+        false => bench.threads.clone(),
+    };
+    println!(
+        "Compressing {} files ({}) with {} thread counts..",
+        files.len(),
+        format_size(total_size),
+        thread_counts.len()
+    );
+    let mut rows = Vec::new();
+    for threads in thread_counts {
+        let output_dir = work_dir.join(format!("output-{threads}"));
+        // All media types keep their format, like a normal run with -i -a -v --output-dir
+        let options = Options {
+            image_ext: Some(Vec::new()),
+            audio_ext: Some(None),
+            video_ext: Some(None),
+            output_dir: Some(output_dir.clone()),
+            keep_files: true,
+            threads: Some(threads),
+            ..Default::default()
+        };
+        if uses_ffmpeg(&files, &options) {
+            check_ffmpeg()?;
+        }
+        fs::create_dir_all(&output_dir)?;
+
+        let start = Instant::now();
+        let progress = Progress::new(multi, &files, &options, ProgressFormat::Text);
+        let results = compress(
+            files.clone(),
+            &options,
+            &Cancellation::new(),
+            None,
+            &|event| progress.update(&event),
+        )?;
+        let elapsed = start.elapsed().as_secs_f64();
+        fs::remove_dir_all(&output_dir).unwrap_or_default();
+        let failed = group_by_source(&results)
+            .filter(|outputs| {
+                outputs
+                    .iter()
+                    .any(|result| result.status == FileOutcome::Failed)
+            })
+            .count();
+        if failed == files.len() {
+            return Err(anyhow!(
+                "All files failed to compress with {threads} threads."
+            ));
+        }
+        rows.push((threads, elapsed, failed));
+    }
+
+    println!(
+        "{:>8} {:>10} {:>10} {:>10}",
+        "threads", "time", "files/s", "MB/s"
+    );
+    for (threads, elapsed, failed) in rows.iter() {
+        println!(
+            "{threads:>8} {:>9.2}s {:>10.2} {:>10.2}{}",
+            elapsed,
+            files.len() as f64 / elapsed,
+            total_size as f64 / (1 << 20) as f64 / elapsed,
+            match failed {
+                0 => String::new(),
+                failed => format!(" ({failed} failed)"),
+            }
+        );
+    }
+    if let Some((threads, _, _)) = rows.iter().min_by(|(_, a, _), (_, b, _)| a.total_cmp(b)) {
+        println!("Fastest with {threads} threads.");
+    }
+    Ok(())
+}
+
+/// Powers of two up to the amount of CPUs, and the amount of CPUs itself
+fn default_thread_counts() -> Vec<usize> {
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let mut counts: Vec<usize> = (0..)
+        .map(|power| 1 << power)
+        .take_while(|&count| count < cpus)
+        .collect();
+    counts.push(cpus);
+    counts
+}
+
+/// Writes fractal images that compress like photos
+fn generate_samples(dir: &Path, count: usize) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create directory {dir:?}."))?;
+    for i in 0..count {
+        let mut wand = MagickWand::new();
+        wand.set_size(SAMPLE_SIZE.0, SAMPLE_SIZE.1)
+            .and_then(|_| wand.read_image("plasma:fractal"))
+            .map_err(|err| anyhow!("Failed to generate sample image: {err}"))?;
+        wand.set_image_compression_quality(95)
+            .map_err(|err| anyhow!("Failed to generate sample image: {err}"))?;
+        let path = dir.join(format!("sample_{i}.jpg"));
+        wand.write_image(&path.to_string_lossy())
+            .map_err(|err| anyhow!("Failed to write sample image: {err}"))?;
+    }
+    Ok(())
+}
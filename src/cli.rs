@@ -0,0 +1,701 @@
+//! Command line arguments, converted to the [`Options`] of the library
+
+use anyhow::{anyhow, Context, Result};
+use clap::{ArgEnum, Parser, Subcommand};
+use comva::{Options, Subsampling};
+use std::{path::PathBuf, time::SystemTime};
+
+#[derive(Parser, Debug, Clone)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// Directories or files to compress, defaults to the current directory
+    #[clap(short, long = "path")]
+    pub paths: Vec<PathBuf>,
+
+    /// Files or directories to compress, in addition to the --path directories
+    #[clap(conflicts_with = "files-from")]
+    pub files: Vec<PathBuf>,
+
+    /// Compress the files listed in this file instead of indexing directories, use - for stdin
+    #[clap(long)]
+    pub files_from: Option<PathBuf>,
+
+    /// Compress image image files, can be given multiple times or separated by commas to write an
+    /// output in each format
+    #[clap(
+        short,
+        min_values = 0,
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        require_value_delimiter = true
+    )]
+    pub image_ext: Option<Vec<String>>,
+
+    /// Compress audio files
+    #[clap(short)]
+    pub audio_ext: Option<Option<String>>,
+
+    /// Compress video files
+    #[clap(short)]
+    pub video_ext: Option<Option<String>>,
+
+    /// Only compress files of this media type, without -i, -a or -v their formats are kept
+    #[clap(long, arg_enum)]
+    pub only: Vec<MediaType>,
+
+    /// Always use lowercase output extensions, by default the casing of the original extension is kept
+    #[clap(long)]
+    pub lowercase_ext: bool,
+
+    /// Fail when an output format is unknown instead of warning
+    #[clap(long)]
+    pub strict: bool,
+
+    /// Don't warn about conversions from lossless to lossy formats, such as FLAC to MP3
+    #[clap(long)]
+    pub allow_lossy: bool,
+
+    /// Output format for an input extension, e.g. png=webp, takes precedence over the formats above
+    #[clap(long = "map", parse(try_from_str = parse_format_map))]
+    pub format_map: Vec<(String, String)>,
+
+    /// Write the compressed files to this directory, mirroring the source directories, originals are kept
+    #[clap(short, long)]
+    pub output_dir: Option<PathBuf>,
+
+    /// Put all outputs directly in the output directory, names that are taken get a _1, _2.. suffix
+    #[clap(long, requires = "output-dir")]
+    pub flatten: bool,
+
+    /// What to do when an output file already exists, rename writes <name>_1.<ext> instead. Files
+    /// with the same output in one run, e.g. song.wav and song.flac, never replace each other's
+    /// output, only rename writes both
+    #[clap(long, arg_enum, default_value = "skip")]
+    pub overwrite_policy: OverwritePolicy,
+
+    /// Check that the output can be decoded before the original is removed
+    #[clap(long)]
+    pub verify: bool,
+
+    /// Keep the original files, originals that are recompressed in place are kept as <name>.backup
+    #[clap(short, long)]
+    pub keep_files: bool,
+
+    /// Move the originals of files that are recompressed in place to this directory, mirroring the
+    /// source directories, instead of keeping a .backup file next to them. Requires --keep-files
+    #[clap(long)]
+    pub backup_dir: Option<PathBuf>,
+
+    /// Keep the modification and access times of the original files (default)
+    #[clap(long, overrides_with = "no-preserve-timestamps")]
+    pub preserve_timestamps: bool,
+
+    /// Don't keep the modification and access times of the original files
+    #[clap(long, overrides_with = "preserve-timestamps")]
+    pub no_preserve_timestamps: bool,
+
+    /// Keep the permissions and, where permitted, the owner of the original files (default)
+    #[clap(long, overrides_with = "no-preserve-perms")]
+    pub preserve_perms: bool,
+
+    /// Don't keep the permissions and owner of the original files
+    #[clap(long, overrides_with = "preserve-perms")]
+    pub no_preserve_perms: bool,
+
+    /// Also compress read-only files, read-only directories are made writable while their files are
+    /// replaced
+    #[clap(long)]
+    pub force: bool,
+
+    /// Ask for confirmation before overwriting or removing original files
+    #[clap(short = 'I', long)]
+    pub interactive: bool,
+
+    /// Continue without asking for confirmation
+    #[clap(short, long)]
+    pub yes: bool,
+
+    /// Print the planned operations without modifying any files
+    #[clap(short = 'n', long)]
+    pub dry_run: bool,
+
+    /// Print the indexed files grouped by media type and exit
+    #[clap(long)]
+    pub list: bool,
+
+    /// Compress the start of every video to estimate the savings of a full run and exit
+    #[clap(long)]
+    pub estimate: bool,
+
+    /// Length in seconds of the compressed sample of each video when estimating
+    #[clap(long, default_value_t = 10, requires = "estimate")]
+    pub estimate_seconds: u32,
+
+    /// Record processed files in this state file and skip files already processed in a previous run
+    #[clap(long)]
+    pub state: Option<PathBuf>,
+
+    /// Format of the progress output, jsonl prints one JSON event per line to stdout for frontends
+    #[clap(long, arg_enum, default_value = "text")]
+    pub progress_format: ProgressFormat,
+
+    /// Write a JSON report of all processed files
+    #[clap(long)]
+    pub report: Option<PathBuf>,
+
+    /// Print the files that saved the most space and the files that grew the most, at most this many each
+    #[clap(long)]
+    pub top: Option<usize>,
+
+    /// Remove all metadata profiles from images
+    #[clap(long)]
+    pub strip_metadata: bool,
+
+    /// Convert images to sRGB, images with an embedded color profile are converted with an sRGB ICC profile
+    #[clap(long)]
+    pub to_srgb: bool,
+
+    /// sRGB ICC profile used by --to-srgb, by default common system locations are searched
+    #[clap(long)]
+    pub srgb_profile: Option<PathBuf>,
+
+    /// Maximum image width in pixels, larger images are downscaled
+    #[clap(long)]
+    pub max_width: Option<usize>,
+
+    /// Maximum image height in pixels, larger images are downscaled
+    #[clap(long)]
+    pub max_height: Option<usize>,
+
+    /// Also write a thumbnail of each image that fits within this amount of pixels
+    #[clap(long)]
+    pub thumbnail: Option<usize>,
+
+    /// PNG compression level (0-9), PNG compression is always lossless
+    #[clap(long)]
+    pub png_level: Option<u8>,
+
+    /// Backend used to optimize PNG output
+    #[clap(long, arg_enum, default_value = "imagemagick")]
+    pub png_backend: PngBackend,
+
+    /// Backend used to encode JPEG output
+    #[clap(long, arg_enum, default_value = "imagemagick")]
+    pub jpeg_backend: JpegBackend,
+
+    /// Chroma subsampling of JPEG output: 4:4:4, 4:2:2 or 4:2:0. Lossy WebP is always 4:2:0, 4:4:4
+    /// and 4:2:2 enable its sharp YUV conversion instead
+    #[clap(long, parse(try_from_str = parse_subsampling))]
+    pub subsampling: Option<Subsampling>,
+
+    /// Use lossless compression for WebP output
+    #[clap(long)]
+    pub webp_lossless: bool,
+
+    /// WebP compression method (0-6), higher is slower but smaller
+    #[clap(long)]
+    pub webp_method: Option<u8>,
+
+    /// AVIF encoding speed (0-10), higher is faster but larger
+    #[clap(long)]
+    pub avif_speed: Option<u8>,
+
+    /// Maximum amount of colors (2-256) when recompressing GIFs, fewer colors give smaller files
+    #[clap(long)]
+    pub gif_colors: Option<usize>,
+
+    /// ImageMagick option to set before writing images, e.g. jpeg:sampling-factor=4:2:0
+    #[clap(long = "magick-define", parse(try_from_str = parse_magick_define))]
+    pub magick_defines: Vec<(String, String)>,
+
+    /// Preset of image, audio and video settings, options and config files take precedence
+    #[clap(long, arg_enum)]
+    pub preset: Option<Preset>,
+
+    /// Image compression quality (1-100)
+    #[clap(short = 'q', long, visible_alias = "quality")]
+    pub image_quality: Option<u16>,
+
+    /// Minimum SSIM (0-1) of lossy image outputs, the quality is raised until it's reached or the
+    /// original is kept
+    #[clap(long)]
+    pub min_ssim: Option<f64>,
+
+    /// Maximum directory depth to index, 0 only indexes the given directories
+    #[clap(long)]
+    pub max_depth: Option<u32>,
+
+    /// Audio bitrate for lossy audio formats, e.g. 96k or 192k
+    #[clap(long)]
+    pub audio_bitrate: Option<String>,
+
+    /// Normalize the loudness of audio files with the EBU R128 loudnorm filter
+    #[clap(long)]
+    pub audio_normalize: bool,
+
+    /// Target integrated loudness in LUFS when normalizing audio, e.g. -16 (default: -24)
+    #[clap(long, allow_hyphen_values = true)]
+    pub audio_lufs: Option<f64>,
+
+    /// Video encoder used by FFMPEG, e.g. libx265, libaom-av1 or libsvtav1, WebM output falls back to libvpx-vp9
+    #[clap(long)]
+    pub video_codec: Option<String>,
+
+    /// Encode video on the GPU with the hardware variant of the video codec
+    #[clap(long, arg_enum)]
+    pub hwaccel: Option<HwAccel>,
+
+    /// Device used for VAAPI encoding
+    #[clap(long, default_value = "/dev/dri/renderD128")]
+    pub vaapi_device: String,
+
+    /// Video constant rate factor (0-51, 0-63 for AV1), lower is better quality
+    #[clap(long)]
+    pub video_crf: Option<u8>,
+
+    /// Video bitrate in kbps, encodes in two passes to hit the bitrate instead of using a CRF
+    #[clap(long)]
+    pub video_target_bitrate: Option<u32>,
+
+    /// Maximum video height in pixels, larger videos are downscaled
+    #[clap(long)]
+    pub video_max_height: Option<u32>,
+
+    /// Maximum video framerate
+    #[clap(long)]
+    pub video_fps: Option<f64>,
+
+    /// Only change the container of audio and video files by copying the streams without
+    /// re-encoding, the codec and quality settings are ignored and outputs are kept regardless of size
+    #[clap(long)]
+    pub copy_streams: bool,
+
+    /// Only keep the audio stream with this index, counting from 0, and the video streams
+    #[clap(long)]
+    pub audio_track: Option<usize>,
+
+    /// Remove subtitle streams from videos
+    #[clap(long)]
+    pub drop_subtitles: bool,
+
+    /// Extra argument passed verbatim to ffmpeg after the built-in arguments, e.g. -map_metadata
+    #[clap(long = "ffmpeg-arg", allow_hyphen_values = true, number_of_values = 1)]
+    pub ffmpeg_args: Vec<String>,
+
+    /// Exclude paths matching a glob pattern, relative to the indexed directory
+    #[clap(long)]
+    pub exclude: Vec<String>,
+
+    /// Keep the original if the output saves less than this percentage
+    #[clap(long, default_value_t = 0.0)]
+    pub min_savings: f64,
+
+    /// Skip files smaller than this size, e.g. 500k or 2M
+    #[clap(long, parse(try_from_str = parse_size))]
+    pub min_size: Option<u64>,
+
+    /// Stop starting new jobs once this much space is saved, e.g. 10G
+    #[clap(long, parse(try_from_str = parse_size))]
+    pub stop_after_saved: Option<u64>,
+
+    /// Stop starting new jobs when less than this much disk space is free where outputs are
+    /// written, e.g. 5G, only supported on Unix
+    #[clap(long, parse(try_from_str = parse_size))]
+    pub min_free_space: Option<u64>,
+
+    /// Order in which the files of each media type are processed
+    #[clap(long, arg_enum)]
+    pub sort_by: Option<SortBy>,
+
+    /// Only compress files modified since this date or duration ago, e.g. 2024-01-31 or 7d
+    #[clap(long, parse(try_from_str = parse_since))]
+    pub since: Option<SystemTime>,
+
+    /// Follow symbolic links while indexing
+    #[clap(long)]
+    pub follow_symlinks: bool,
+
+    /// Skip hidden files and directories, whose name starts with a dot (default)
+    #[clap(long, overrides_with = "no-ignore-hidden")]
+    pub ignore_hidden: bool,
+
+    /// Also index hidden files and directories
+    #[clap(long, overrides_with = "ignore-hidden")]
+    pub no_ignore_hidden: bool,
+
+    /// Remove the directories that became empty because their originals were removed or moved
+    #[clap(long)]
+    pub prune_empty_dirs: bool,
+
+    /// Detect the media type by file content even when the extension is known, files without an
+    /// extension or with an unknown extension are always detected by content
+    #[clap(long)]
+    pub detect_content: bool,
+
+    /// Abort the compression of a file after this amount of seconds
+    #[clap(long)]
+    pub timeout: Option<u64>,
+
+    /// Retry failed FFMPEG commands this many times, for transient errors such as flaky storage
+    #[clap(long, default_value_t = 0)]
+    pub retries: u32,
+
+    /// The amount of worker threads, 0 or auto uses the amount of CPUs (default)
+    #[clap(short, long, visible_alias = "threads-cpu", parse(try_from_str = parse_threads))]
+    pub threads: Option<usize>,
+
+    /// Threads that read files ahead of the workers, keeps the CPU busy when the disk is slow
+    #[clap(long, conflicts_with = "concurrency-per-directory")]
+    pub threads_io: Option<usize>,
+
+    /// Maximum amount of jobs in the same directory at once, reduces seeking on hard disks
+    #[clap(long)]
+    pub concurrency_per_directory: Option<usize>,
+
+    /// Separate limit for the amount of images compressed at once, image compression uses a lot of memory
+    #[clap(long)]
+    pub image_threads: Option<usize>,
+
+    /// Show more output such as the FFMPEG commands and timings, repeat it (--verbose --verbose) to
+    /// also show trace output. There is no short flag, -v sets the video extension
+    #[clap(long, parse(from_occurrences), conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only show errors and the final summary. There is no short flag, -q sets the image quality
+    #[clap(long)]
+    pub quiet: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Action>,
+}
+
+/// Commands besides compressing, which is the default
+#[derive(Subcommand, Debug, Clone)]
+pub enum Action {
+    /// Measure the compression throughput at different thread counts
+    Bench(BenchOptions),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct BenchOptions {
+    /// Thread counts to measure, separated by commas, defaults to 1, 2, 4.. up to the amount of CPUs
+    #[clap(long, use_value_delimiter = true)]
+    pub threads: Vec<usize>,
+
+    /// Measure with the media files in this directory instead of generated images
+    #[clap(short, long)]
+    pub path: Option<PathBuf>,
+
+    /// Amount of images to generate
+    #[clap(long, default_value_t = 16)]
+    pub samples: usize,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// Progress bars and a summary for humans
+    Text,
+    /// JSON Lines events, without progress bars or summary
+    Jsonl,
+}
+
+/// Defines an argument value for the enum of the library with the same name and variants
+macro_rules! arg_enum {
+    ($name:ident { $($(#[$variant_meta:meta])* $variant:ident,)* }) => {
+        #[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)*
+        }
+
+        impl From<$name> for comva::$name {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => comva::$name::$variant,)*
+                }
+            }
+        }
+    };
+}
+
+arg_enum!(MediaType {
+    Image,
+    Audio,
+    Video,
+});
+
+arg_enum!(OverwritePolicy {
+    /// Keep the existing file and skip the file
+    Skip,
+    /// Replace the existing file, outputs that another file of the run writes to are skipped
+    Overwrite,
+    /// Write the output next to it with a _1, _2.. suffix
+    Rename,
+});
+
+arg_enum!(PngBackend {
+    Imagemagick,
+    /// Optimize the written PNG further with oxipng
+    Oxipng,
+});
+
+arg_enum!(JpegBackend {
+    Imagemagick,
+    /// Encode with mozjpeg, which usually produces smaller files at the same quality
+    Mozjpeg,
+});
+
+arg_enum!(HwAccel {
+    /// Intel and AMD GPUs on Linux
+    Vaapi,
+    /// NVIDIA GPUs
+    Nvenc,
+    /// Intel Quick Sync Video
+    Qsv,
+});
+
+arg_enum!(SortBy {
+    /// Smallest files first
+    Size,
+    /// Largest files first, saves the most space soonest
+    SizeDesc,
+    /// Oldest files first
+    Mtime,
+    /// Most recently modified files first
+    MtimeDesc,
+});
+
+arg_enum!(Preset {
+    /// Smaller files for sharing and websites, at most 1080p
+    Web,
+    /// High quality copies for long-term storage
+    Archive,
+    /// The smallest files, at a visible loss of quality
+    MaxCompression,
+});
+
+impl Args {
+    /// The options of the library, settings that only affect the command line are left out
+    pub fn options(&self) -> Options {
+        Options {
+            image_ext: self.image_ext.clone(),
+            audio_ext: self.audio_ext.clone(),
+            video_ext: self.video_ext.clone(),
+            lowercase_ext: self.lowercase_ext,
+            strict: self.strict,
+            allow_lossy: self.allow_lossy,
+            format_map: self.format_map.clone(),
+            output_dir: self.output_dir.clone(),
+            flatten: self.flatten,
+            overwrite_policy: self.overwrite_policy.into(),
+            verify: self.verify,
+            keep_files: self.keep_files,
+            backup_dir: self.backup_dir.clone(),
+            no_preserve_timestamps: self.no_preserve_timestamps,
+            no_preserve_perms: self.no_preserve_perms,
+            force: self.force,
+            dry_run: self.dry_run,
+            strip_metadata: self.strip_metadata,
+            to_srgb: self.to_srgb,
+            srgb_profile: self.srgb_profile.clone(),
+            max_width: self.max_width,
+            max_height: self.max_height,
+            thumbnail: self.thumbnail,
+            png_level: self.png_level,
+            png_backend: self.png_backend.into(),
+            jpeg_backend: self.jpeg_backend.into(),
+            subsampling: self.subsampling,
+            webp_lossless: self.webp_lossless,
+            webp_method: self.webp_method,
+            avif_speed: self.avif_speed,
+            gif_colors: self.gif_colors,
+            magick_defines: self.magick_defines.clone(),
+            preset: self.preset.map(Into::into),
+            image_quality: self.image_quality,
+            min_ssim: self.min_ssim,
+            audio_bitrate: self.audio_bitrate.clone(),
+            audio_normalize: self.audio_normalize,
+            audio_lufs: self.audio_lufs,
+            video_codec: self.video_codec.clone(),
+            hwaccel: self.hwaccel.map(Into::into),
+            vaapi_device: Some(self.vaapi_device.clone()),
+            video_crf: self.video_crf,
+            video_target_bitrate: self.video_target_bitrate,
+            video_max_height: self.video_max_height,
+            video_fps: self.video_fps,
+            copy_streams: self.copy_streams,
+            audio_track: self.audio_track,
+            drop_subtitles: self.drop_subtitles,
+            ffmpeg_args: self.ffmpeg_args.clone(),
+            exclude: self.exclude.clone(),
+            min_savings: self.min_savings,
+            min_size: self.min_size,
+            stop_after_saved: self.stop_after_saved,
+            min_free_space: self.min_free_space,
+            sort_by: self.sort_by.map(Into::into),
+            timeout: self.timeout,
+            retries: self.retries,
+            threads: self.threads,
+            threads_io: self.threads_io,
+            concurrency_per_directory: self.concurrency_per_directory,
+            image_threads: self.image_threads,
+        }
+    }
+}
+
+/// Parses an output format mapping in the form <from>=<to>
+fn parse_format_map(input: &str) -> Result<(String, String)> {
+    let (from, to) = input
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid format mapping '{input}', expected <from>=<to>."))?;
+    let (from, to) = (from.trim().to_lowercase(), to.trim().to_lowercase());
+    if from.is_empty() || to.is_empty() {
+        return Err(anyhow!(
+            "Invalid format mapping '{input}', expected <from>=<to>."
+        ));
+    }
+    Ok((from, to))
+}
+
+/// Parses a thread count, auto uses the amount of CPUs
+fn parse_threads(input: &str) -> Result<usize> {
+    if input.eq_ignore_ascii_case("auto") {
+        return Ok(0);
+    }
+    input
+        .parse()
+        .map_err(|_| anyhow!("Invalid thread count '{input}', expected a number or auto."))
+}
+
+/// Parses an ImageMagick option in the form <key>=<value>
+fn parse_magick_define(input: &str) -> Result<(String, String)> {
+    let (key, value) = input
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Invalid ImageMagick option '{input}', expected <key>=<value>."))?;
+    let (key, value) = (key.trim(), value.trim());
+    if key.is_empty() {
+        return Err(anyhow!(
+            "Invalid ImageMagick option '{input}', expected <key>=<value>."
+        ));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parses a date, optionally with a time, or a duration before now such as 7d or 12h
+fn parse_since(input: &str) -> Result<SystemTime> {
+    if let Ok(duration) = humantime::parse_duration(input) {
+        return SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| anyhow!("Duration '{input}' is too long."));
+    }
+    // Dates without a time start at midnight
+    let timestamp = if input.len() == 10 {
+        format!("{input} 00:00:00")
+    } else {
+        input.to_string()
+    };
+    humantime::parse_rfc3339_weak(&timestamp).map_err(|_| {
+        anyhow!("Invalid date '{input}', expected e.g. 2024-01-31, 2024-01-31T12:00:00Z or 7d.")
+    })
+}
+
+/// Parses a size in bytes with an optional binary unit suffix, e.g. 500k or 2M
+fn parse_size(input: &str) -> Result<u64> {
+    let input = input.trim().to_lowercase();
+    let number_end = input
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(number_end);
+    let multiplier: u64 = match unit.trim() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        unit => return Err(anyhow!("Unknown size unit '{unit}'")),
+    };
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid size '{input}'"))?;
+    let size = number * multiplier as f64;
+    // Casting saturates, so sizes beyond u64 would silently become the maximum
+    if size >= u64::MAX as f64 {
+        return Err(anyhow!("Size '{input}' is too large"));
+    }
+    Ok(size as u64)
+}
+
+fn parse_subsampling(input: &str) -> Result<Subsampling> {
+    match input {
+        "4:4:4" => Ok(Subsampling::Full),
+        "4:2:2" => Ok(Subsampling::Half),
+        "4:2:0" => Ok(Subsampling::Quarter),
+        _ => Err(anyhow!(
+            "Invalid subsampling '{input}', expected 4:4:4, 4:2:2 or 4:2:0."
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_since_dates() {
+        let date = parse_since("2024-01-31").unwrap();
+        assert_eq!(
+            date,
+            humantime::parse_rfc3339("2024-01-31T00:00:00Z").unwrap()
+        );
+        let timestamp = parse_since("2024-01-31T12:30:00Z").unwrap();
+        assert_eq!(
+            timestamp.duration_since(date).unwrap(),
+            Duration::from_secs(12 * 3600 + 30 * 60)
+        );
+    }
+
+    #[test]
+    fn parse_since_durations() {
+        let before = SystemTime::now();
+        let since = parse_since("2d").unwrap();
+        let age = before.duration_since(since).unwrap_or_default();
+        assert!(age <= Duration::from_secs(2 * 86400));
+        assert!(age > Duration::from_secs(2 * 86400 - 60));
+    }
+
+    #[test]
+    fn parse_since_rejects_invalid() {
+        assert!(parse_since("").is_err());
+        assert!(parse_since("yesterday").is_err());
+        assert!(parse_since("2024-13-01").is_err());
+        assert!(parse_since("999999999999y").is_err());
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("1500").unwrap(), 1500);
+        assert_eq!(parse_size("10b").unwrap(), 10);
+        assert_eq!(parse_size("2K").unwrap(), 2 << 10);
+        assert_eq!(parse_size("2kb").unwrap(), 2 << 10);
+        assert_eq!(parse_size("3M").unwrap(), 3 << 20);
+        assert_eq!(parse_size("3mib").unwrap(), 3 << 20);
+        assert_eq!(parse_size("4g").unwrap(), 4 << 30);
+        assert_eq!(parse_size("4GB").unwrap(), 4 << 30);
+    }
+
+    #[test]
+    fn parse_size_decimals_and_whitespace() {
+        assert_eq!(parse_size("1.5M").unwrap(), 3 << 19);
+        assert_eq!(parse_size("0.5k").unwrap(), 512);
+        assert_eq!(parse_size(" 100 MB ").unwrap(), 100 << 20);
+    }
+
+    #[test]
+    fn parse_size_rejects_invalid() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.2.3").is_err());
+        assert!(parse_size("10x").is_err());
+        assert!(parse_size("99999999999t").is_err());
+    }
+}
@@ -0,0 +1,3740 @@
+//! Compression and conversion of media files in bulk, the engine behind the comva command line tool
+mod magick_ffi;
+
+use anyhow::{anyhow, Context, Result};
+use filetime::FileTime;
+use globset::GlobSet;
+use log::{debug, error, info, warn};
+use magick_rust::{bindings, magick_wand_genesis, MagickWand};
+use rayon::{prelude::*, ThreadPoolBuilder};
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fs,
+    io::{self, Read, Write},
+    panic,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, LazyLock, Mutex, Once,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Settings of a compression run, see [`compress`]
+///
+/// The defaults compress nothing, a format has to be set for every media type that is compressed.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Output formats of images, every format gets its own output, an empty list keeps the format
+    /// and `None` leaves images out
+    pub image_ext: Option<Vec<String>>,
+
+    /// Output format of audio files, `Some(None)` keeps the format and `None` leaves them out
+    pub audio_ext: Option<Option<String>>,
+
+    /// Output format of video files, `Some(None)` keeps the format and `None` leaves them out
+    pub video_ext: Option<Option<String>>,
+
+    /// Always use lowercase output extensions, by default the casing of the original extension is kept
+    pub lowercase_ext: bool,
+
+    /// Fail when an output format is unknown instead of warning
+    pub strict: bool,
+
+    /// Don't warn about conversions from lossless to lossy formats, such as FLAC to MP3
+    pub allow_lossy: bool,
+
+    /// Output format for an input extension, e.g. (png, webp), takes precedence over the formats above
+    pub format_map: Vec<(String, String)>,
+
+    /// Write the compressed files to this directory, mirroring the source directories, originals are kept
+    pub output_dir: Option<PathBuf>,
+
+    /// Put all outputs directly in the output directory, names that are taken get a _1, _2.. suffix
+    pub flatten: bool,
+
+    /// What to do when an output file already exists, rename writes <name>_1.<ext> instead
+    pub overwrite_policy: OverwritePolicy,
+
+    /// Check that the output can be decoded before the original is removed
+    pub verify: bool,
+
+    /// Keep the original files, originals that are recompressed in place are kept as <name>.backup
+    pub keep_files: bool,
+
+    /// Move the originals of files that are recompressed in place to this directory, mirroring the
+    /// source directories, instead of keeping a .backup file next to them. Requires `keep_files`
+    pub backup_dir: Option<PathBuf>,
+
+    /// Don't keep the modification and access times of the original files
+    pub no_preserve_timestamps: bool,
+
+    /// Don't keep the permissions and owner of the original files
+    pub no_preserve_perms: bool,
+
+    /// Also compress read-only files, read-only directories are made writable while their files are
+    /// replaced
+    pub force: bool,
+
+    /// Print the planned operations without modifying any files
+    pub dry_run: bool,
+
+    /// Remove all metadata profiles from images
+    pub strip_metadata: bool,
+
+    /// Convert images to sRGB, images with an embedded color profile are converted with an sRGB ICC profile
+    pub to_srgb: bool,
+
+    /// sRGB ICC profile used by `to_srgb`, see [`SRGB_PROFILES`] for common system locations
+    pub srgb_profile: Option<PathBuf>,
+
+    /// Maximum image width in pixels, larger images are downscaled
+    pub max_width: Option<usize>,
+
+    /// Maximum image height in pixels, larger images are downscaled
+    pub max_height: Option<usize>,
+
+    /// Also write a thumbnail of each image that fits within this amount of pixels
+    pub thumbnail: Option<usize>,
+
+    /// PNG compression level (0-9), PNG compression is always lossless
+    pub png_level: Option<u8>,
+
+    /// Backend used to optimize PNG output
+    pub png_backend: PngBackend,
+
+    /// Backend used to encode JPEG output
+    pub jpeg_backend: JpegBackend,
+
+    /// Chroma subsampling of JPEG output: 4:4:4, 4:2:2 or 4:2:0. Lossy WebP is always 4:2:0, 4:4:4
+    /// and 4:2:2 enable its sharp YUV conversion instead
+    pub subsampling: Option<Subsampling>,
+
+    /// Use lossless compression for WebP output
+    pub webp_lossless: bool,
+
+    /// WebP compression method (0-6), higher is slower but smaller
+    pub webp_method: Option<u8>,
+
+    /// AVIF encoding speed (0-10), higher is faster but larger
+    pub avif_speed: Option<u8>,
+
+    /// Maximum amount of colors (2-256) when recompressing GIFs, fewer colors give smaller files
+    pub gif_colors: Option<usize>,
+
+    /// ImageMagick options to set before writing images, e.g. (jpeg:sampling-factor, 4:2:0)
+    pub magick_defines: Vec<(String, String)>,
+
+    /// Preset of image, audio and video settings, see [`Preset::config`]
+    pub preset: Option<Preset>,
+
+    /// Image compression quality (1-100)
+    pub image_quality: Option<u16>,
+
+    /// Minimum SSIM (0-1) of lossy image outputs, the quality is raised until it's reached or the
+    /// original is kept
+    pub min_ssim: Option<f64>,
+
+    /// Audio bitrate for lossy audio formats, e.g. 96k or 192k
+    pub audio_bitrate: Option<String>,
+
+    /// Normalize the loudness of audio files with the EBU R128 loudnorm filter
+    pub audio_normalize: bool,
+
+    /// Target integrated loudness in LUFS when normalizing audio, e.g. -16 (default: -24)
+    pub audio_lufs: Option<f64>,
+
+    /// Video encoder used by FFMPEG, e.g. libx265, libaom-av1 or libsvtav1, WebM output falls back to libvpx-vp9
+    pub video_codec: Option<String>,
+
+    /// Encode video on the GPU with the hardware variant of the video codec
+    pub hwaccel: Option<HwAccel>,
+
+    /// Device used for VAAPI encoding, defaults to /dev/dri/renderD128
+    pub vaapi_device: Option<String>,
+
+    /// Video constant rate factor (0-51, 0-63 for AV1), lower is better quality
+    pub video_crf: Option<u8>,
+
+    /// Video bitrate in kbps, encodes in two passes to hit the bitrate instead of using a CRF
+    pub video_target_bitrate: Option<u32>,
+
+    /// Maximum video height in pixels, larger videos are downscaled
+    pub video_max_height: Option<u32>,
+
+    /// Maximum video framerate
+    pub video_fps: Option<f64>,
+
+    /// Only change the container of audio and video files by copying the streams without
+    /// re-encoding, the codec and quality settings are ignored and outputs are kept regardless of size
+    pub copy_streams: bool,
+
+    /// Only keep the audio stream with this index, counting from 0, and the video streams
+    pub audio_track: Option<usize>,
+
+    /// Remove subtitle streams from videos
+    pub drop_subtitles: bool,
+
+    /// Extra arguments passed verbatim to ffmpeg after the built-in arguments, e.g. -map_metadata
+    pub ffmpeg_args: Vec<String>,
+
+    /// Glob patterns of paths to exclude, used to build [`IndexSettings::exclude`]
+    pub exclude: Vec<String>,
+
+    /// Keep the original if the output saves less than this percentage
+    pub min_savings: f64,
+
+    /// Skip files smaller than this size in bytes
+    pub min_size: Option<u64>,
+
+    /// Stop starting new jobs once this many bytes are saved
+    pub stop_after_saved: Option<u64>,
+
+    /// Stop starting new jobs when fewer bytes than this are free where outputs are written, only
+    /// supported on Unix
+    pub min_free_space: Option<u64>,
+
+    /// Order in which the files of each media type are processed
+    pub sort_by: Option<SortBy>,
+
+    /// Abort the compression of a file after this amount of seconds
+    pub timeout: Option<u64>,
+
+    /// Retry failed FFMPEG commands this many times, for transient errors such as flaky storage
+    pub retries: u32,
+
+    /// The amount of worker threads, `None` or 0 uses the amount of CPUs
+    pub threads: Option<usize>,
+
+    /// Threads that read files ahead of the workers, keeps the CPU busy when the disk is slow
+    pub threads_io: Option<usize>,
+
+    /// Maximum amount of jobs in the same directory at once, reduces seeking on hard disks
+    pub concurrency_per_directory: Option<usize>,
+
+    /// Separate limit for the amount of images compressed at once, image compression uses a lot of memory
+    pub image_threads: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PngBackend {
+    #[default]
+    Imagemagick,
+    /// Optimize the written PNG further with oxipng
+    Oxipng,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HwAccel {
+    /// Intel and AMD GPUs on Linux
+    Vaapi,
+    /// NVIDIA GPUs
+    Nvenc,
+    /// Intel Quick Sync Video
+    Qsv,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum JpegBackend {
+    #[default]
+    Imagemagick,
+    /// Encode with mozjpeg, which usually produces smaller files at the same quality
+    Mozjpeg,
+}
+
+/// Chroma subsampling, the resolution of the color channels relative to the brightness
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Subsampling {
+    Full,
+    Half,
+    Quarter,
+}
+
+impl Subsampling {
+    fn name(self) -> &'static str {
+        match self {
+            Subsampling::Full => "4:4:4",
+            Subsampling::Half => "4:2:2",
+            Subsampling::Quarter => "4:2:0",
+        }
+    }
+
+    /// Size of a chroma pixel in luma pixels
+    fn pixel_size(self) -> (u8, u8) {
+        match self {
+            Subsampling::Full => (1, 1),
+            Subsampling::Half => (2, 1),
+            Subsampling::Quarter => (2, 2),
+        }
+    }
+}
+
+impl Options {
+    /// Validates option values before any files are touched
+    pub fn validate(&self) -> Result<()> {
+        let formats = self.image_ext.iter().flatten().chain(
+            [&self.audio_ext, &self.video_ext]
+                .into_iter()
+                .flatten()
+                .flatten(),
+        );
+        for format in formats {
+            // A file argument right after -i, -a or -v is parsed as its output format
+            if format.contains(['/', '\\']) {
+                return Err(anyhow!(
+                    "Invalid output format '{format}', put files before -i, -a and -v or after --."
+                ));
+            }
+        }
+        if let Some(crf) = self.video_crf {
+            let codec = VideoCodec::from_name(self.video_codec.as_deref());
+            if crf > codec.max_crf {
+                return Err(anyhow!(
+                    "Video CRF for {} must be between 0 and {}, got {crf}.",
+                    codec.encoder,
+                    codec.max_crf
+                ));
+            }
+        }
+        if let Some(bitrate) = &self.audio_bitrate {
+            let digits = bitrate.trim_end_matches(['k', 'K', 'm', 'M']);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+                return Err(anyhow!(
+                    "Invalid audio bitrate '{bitrate}', expected e.g. 96k or 192k."
+                ));
+            }
+        }
+        if self.video_target_bitrate == Some(0) {
+            return Err(anyhow!("Video target bitrate must be at least 1 kbps."));
+        }
+        if let Some(lufs) = self.audio_lufs {
+            // The range supported by the loudnorm filter
+            if !(-70.0..=-5.0).contains(&lufs) {
+                return Err(anyhow!(
+                    "Audio loudness must be between -70 and -5 LUFS, got {lufs}."
+                ));
+            }
+        }
+        if let Some(fps) = self.video_fps {
+            if fps.is_nan() || fps <= 0.0 {
+                return Err(anyhow!("Video FPS must be a positive number, got {fps}."));
+            }
+        }
+        if self.backup_dir.is_some() && !self.keep_files {
+            return Err(anyhow!("A backup directory requires --keep-files."));
+        }
+        if let Some(quality) = self.image_quality {
+            if !(1..=100).contains(&quality) {
+                return Err(anyhow!(
+                    "Image quality must be between 1 and 100, got {quality}."
+                ));
+            }
+        }
+        if let Some(level) = self.png_level {
+            if level > 9 {
+                return Err(anyhow!("PNG level must be between 0 and 9, got {level}."));
+            }
+        }
+        if let Some(method) = self.webp_method {
+            if method > 6 {
+                return Err(anyhow!(
+                    "WebP method must be between 0 and 6, got {method}."
+                ));
+            }
+        }
+        if let Some(speed) = self.avif_speed {
+            if speed > 10 {
+                return Err(anyhow!("AVIF speed must be between 0 and 10, got {speed}."));
+            }
+        }
+        if let Some(colors) = self.gif_colors {
+            if !(2..=256).contains(&colors) {
+                return Err(anyhow!(
+                    "GIF colors must be between 2 and 256, got {colors}."
+                ));
+            }
+        }
+        // The free space is only known on Unix
+        if cfg!(not(unix)) && self.min_free_space.is_some() {
+            return Err(anyhow!("Minimum free space is only supported on Unix."));
+        }
+        if self.image_threads == Some(0) {
+            return Err(anyhow!("Image threads must be at least 1."));
+        }
+        if self.concurrency_per_directory == Some(0) {
+            return Err(anyhow!("Concurrency per directory must be at least 1."));
+        }
+        if let Some(min_ssim) = self.min_ssim {
+            if !(0.0..=1.0).contains(&min_ssim) {
+                return Err(anyhow!(
+                    "Minimum SSIM must be between 0 and 1, got {min_ssim}."
+                ));
+            }
+        }
+        if !(0.0..=100.0).contains(&self.min_savings) {
+            return Err(anyhow!(
+                "Minimum savings must be between 0 and 100%, got {}.",
+                self.min_savings
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Handling of output files that already exist
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    /// Keep the existing file and skip the file
+    #[default]
+    Skip,
+    /// Replace the existing file, outputs that another file of the run writes to are skipped
+    Overwrite,
+    /// Write the output next to it with a _1, _2.. suffix
+    Rename,
+}
+
+/// Processing order of the files within a media type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortBy {
+    /// Smallest files first
+    Size,
+    /// Largest files first, saves the most space soonest
+    SizeDesc,
+    /// Oldest files first
+    Mtime,
+    /// Most recently modified files first
+    MtimeDesc,
+}
+
+/// Named bundle of settings for common use cases
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Smaller files for sharing and websites, at most 1080p
+    Web,
+    /// High quality copies for long-term storage
+    Archive,
+    /// The smallest files, at a visible loss of quality
+    MaxCompression,
+}
+
+impl Preset {
+    /// Settings of the preset, applied like a config file
+    pub fn config(self) -> Config {
+        match self {
+            Preset::Web => Config {
+                image_quality: Some(80),
+                max_width: Some(1920),
+                max_height: Some(1080),
+                audio_bitrate: Some("96k".to_string()),
+                video_crf: Some(30),
+                video_max_height: Some(1080),
+                ..Default::default()
+            },
+            Preset::Archive => Config {
+                image_quality: Some(95),
+                png_level: Some(9),
+                audio_bitrate: Some("256k".to_string()),
+                video_crf: Some(20),
+                ..Default::default()
+            },
+            Preset::MaxCompression => Config {
+                image_quality: Some(60),
+                png_level: Some(9),
+                webp_method: Some(6),
+                avif_speed: Some(2),
+                strip_metadata: true,
+                audio_bitrate: Some("64k".to_string()),
+                video_crf: Some(34),
+                video_max_height: Some(720),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Default settings loaded from a configuration file, options given on the command line take precedence
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    keep_files: bool,
+    backup_dir: Option<PathBuf>,
+    verify: bool,
+    strip_metadata: bool,
+    exclude: Vec<String>,
+    map: HashMap<String, String>,
+    preset: Option<Preset>,
+    #[serde(alias = "quality")]
+    image_quality: Option<u16>,
+    min_ssim: Option<f64>,
+    max_width: Option<usize>,
+    max_height: Option<usize>,
+    png_level: Option<u8>,
+    webp_lossless: bool,
+    webp_method: Option<u8>,
+    avif_speed: Option<u8>,
+    gif_colors: Option<usize>,
+    audio_bitrate: Option<String>,
+    audio_normalize: bool,
+    audio_lufs: Option<f64>,
+    video_codec: Option<String>,
+    video_crf: Option<u8>,
+    video_target_bitrate: Option<u32>,
+    video_max_height: Option<u32>,
+    video_fps: Option<f64>,
+    threads: Option<usize>,
+    threads_io: Option<usize>,
+    image_threads: Option<usize>,
+    timeout: Option<u64>,
+}
+
+impl Config {
+    /// Configuration files in order of precedence
+    pub fn paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from("comva.toml")];
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        if let Some(config_dir) = config_dir {
+            paths.push(config_dir.join("comva").join("config.toml"));
+        }
+        paths
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {path:?}."))?;
+        toml::from_str(&contents).with_context(|| format!("Invalid config file {path:?}."))
+    }
+}
+
+impl Options {
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+
+    /// The amount of worker threads, resolving 0 to the amount of CPUs
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            Some(threads) if threads > 0 => threads,
+            _ => thread::available_parallelism().map_or(1, |threads| threads.get()),
+        }
+    }
+
+    /// Uses the config values for all options that are not set yet
+    pub fn apply_config(&mut self, config: Config) {
+        self.keep_files |= config.keep_files;
+        self.backup_dir = self.backup_dir.take().or(config.backup_dir);
+        self.verify |= config.verify;
+        self.strip_metadata |= config.strip_metadata;
+        self.exclude.extend(config.exclude);
+        // Mappings given on the command line are matched first
+        self.format_map.extend(config.map);
+        self.preset = self.preset.or(config.preset);
+        self.image_quality = self.image_quality.or(config.image_quality);
+        self.min_ssim = self.min_ssim.or(config.min_ssim);
+        self.max_width = self.max_width.or(config.max_width);
+        self.max_height = self.max_height.or(config.max_height);
+        self.png_level = self.png_level.or(config.png_level);
+        self.webp_lossless |= config.webp_lossless;
+        self.webp_method = self.webp_method.or(config.webp_method);
+        self.avif_speed = self.avif_speed.or(config.avif_speed);
+        self.gif_colors = self.gif_colors.or(config.gif_colors);
+        self.audio_bitrate = self.audio_bitrate.take().or(config.audio_bitrate);
+        self.audio_normalize |= config.audio_normalize;
+        self.audio_lufs = self.audio_lufs.or(config.audio_lufs);
+        self.video_codec = self.video_codec.take().or(config.video_codec);
+        self.video_crf = self.video_crf.or(config.video_crf);
+        self.video_target_bitrate = self.video_target_bitrate.or(config.video_target_bitrate);
+        self.video_max_height = self.video_max_height.or(config.video_max_height);
+        self.video_fps = self.video_fps.or(config.video_fps);
+        self.threads = self.threads.or(config.threads);
+        self.threads_io = self.threads_io.or(config.threads_io);
+        self.image_threads = self.image_threads.or(config.image_threads);
+        self.timeout = self.timeout.or(config.timeout);
+    }
+
+    /// Applies a [`DIRECTORY_CONFIG`], its settings take precedence over the current options
+    pub fn apply_override(&mut self, config: Config) -> Result<()> {
+        if config.keep_files
+            || config.backup_dir.is_some()
+            || !config.exclude.is_empty()
+            || !config.map.is_empty()
+            || config.threads.is_some()
+            || config.threads_io.is_some()
+            || config.image_threads.is_some()
+        {
+            return Err(anyhow!(
+                "Directory configs can't set keep-files, backup-dir, exclude, map or thread counts."
+            ));
+        }
+        // Settings in the same file take precedence over its preset
+        if let Some(preset) = config.preset {
+            self.apply_override(preset.config())?;
+        }
+        self.verify |= config.verify;
+        self.strip_metadata |= config.strip_metadata;
+        self.image_quality = config.image_quality.or(self.image_quality);
+        self.min_ssim = config.min_ssim.or(self.min_ssim);
+        self.max_width = config.max_width.or(self.max_width);
+        self.max_height = config.max_height.or(self.max_height);
+        self.png_level = config.png_level.or(self.png_level);
+        self.webp_lossless |= config.webp_lossless;
+        self.webp_method = config.webp_method.or(self.webp_method);
+        self.avif_speed = config.avif_speed.or(self.avif_speed);
+        self.gif_colors = config.gif_colors.or(self.gif_colors);
+        self.audio_bitrate = config.audio_bitrate.or(self.audio_bitrate.take());
+        self.audio_normalize |= config.audio_normalize;
+        self.audio_lufs = config.audio_lufs.or(self.audio_lufs);
+        self.video_codec = config.video_codec.or(self.video_codec.take());
+        self.video_crf = config.video_crf.or(self.video_crf);
+        self.video_target_bitrate = config.video_target_bitrate.or(self.video_target_bitrate);
+        self.video_max_height = config.video_max_height.or(self.video_max_height);
+        self.video_fps = config.video_fps.or(self.video_fps);
+        self.timeout = config.timeout.or(self.timeout);
+        Ok(())
+    }
+}
+
+/// Type category of media
+#[derive(Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    Image,
+    Audio,
+    Video,
+}
+
+// ImageQuick initialization
+static IM_START: Once = Once::new();
+
+/// Extensions that are indexed and their media types
+pub fn default_extensions() -> HashMap<&'static str, MediaType> {
+    HashMap::from([
+        ("gif", MediaType::Image),
+        ("jpg", MediaType::Image),
+        ("jpeg", MediaType::Image),
+        ("png", MediaType::Image),
+        ("bmp", MediaType::Image),
+        ("webp", MediaType::Image),
+        ("avif", MediaType::Image),
+        ("heic", MediaType::Image),
+        ("heif", MediaType::Image),
+        ("tif", MediaType::Image),
+        ("tiff", MediaType::Image),
+        ("cr2", MediaType::Image),
+        ("nef", MediaType::Image),
+        ("mp4", MediaType::Video),
+        ("avi", MediaType::Video),
+        ("mov", MediaType::Video),
+        ("flv", MediaType::Video),
+        ("mkv", MediaType::Video),
+        ("mp3", MediaType::Audio),
+        ("wav", MediaType::Audio),
+        ("ogg", MediaType::Audio),
+        ("flac", MediaType::Audio),
+        ("opus", MediaType::Audio),
+        ("m4a", MediaType::Audio),
+        ("webm", MediaType::Video),
+    ])
+}
+
+static EXTENSIONS: LazyLock<HashMap<&str, MediaType>> = LazyLock::new(default_extensions);
+
+/// Media type of a file by its extension, using the [`default_extensions`]
+pub fn classify(path: &Path) -> Option<MediaType> {
+    let ext = path.extension()?.to_string_lossy().to_lowercase();
+    EXTENSIONS.get(ext.as_str()).cloned()
+}
+
+/// Initializes ImageMagick, must be called before any images are compressed
+pub fn init_imagemagick() {
+    IM_START.call_once(|| {
+        magick_wand_genesis();
+    });
+}
+
+/// Checks if any file in the index is compressed with FFMPEG
+pub fn uses_ffmpeg(index: &[MediaIndex], options: &Options) -> bool {
+    index.iter().any(|file| {
+        output_targets(file, options).iter().any(|(_, output_ext)| {
+            file.media_type != MediaType::Image || ANIMATION_FORMATS.contains(&output_ext.as_str())
+        })
+    })
+}
+
+/// Removes the directories that became empty because their originals were removed or moved, up to
+/// the indexed directory they are in, returns the amount of removed directories
+///
+/// Only directories that are indexed with `settings` are removed, so directories that were empty
+/// before or that are hidden or excluded are never touched.
+pub fn prune_empty_dirs(
+    results: &[FileResult],
+    roots: &[PathBuf],
+    settings: &IndexSettings,
+) -> usize {
+    let removed: HashSet<&Path> = results
+        .iter()
+        .map(|result| result.source_path.as_path())
+        .filter(|path| !path.exists())
+        .collect();
+    let mut pruned = 0;
+    for source_path in removed {
+        let Some(root) = roots
+            .iter()
+            .filter(|root| source_path.starts_with(root))
+            .max_by_key(|root| root.components().count())
+        else {
+            continue;
+        };
+        for dir in source_path.ancestors().skip(1) {
+            if dir == root || !is_indexed_dir(dir, root, settings) {
+                break;
+            }
+            // Only succeeds for empty directories
+            if fs::remove_dir(dir).is_err() {
+                break;
+            }
+            debug!("Removed empty directory {dir:?}");
+            pruned += 1;
+        }
+    }
+    pruned
+}
+
+/// Whether a directory below `root` is indexed with the settings
+fn is_indexed_dir(dir: &Path, root: &Path, settings: &IndexSettings) -> bool {
+    let Ok(relative_path) = dir.strip_prefix(root) else {
+        return false;
+    };
+    let hidden = relative_path
+        .components()
+        .any(|component| component.as_os_str().to_string_lossy().starts_with('.'));
+    if (settings.ignore_hidden && hidden) || settings.exclude.is_match(relative_path) {
+        return false;
+    }
+    let depth = relative_path.components().count() as u32;
+    settings.max_depth.is_none_or(|max| depth <= max)
+        && !settings
+            .skip_dirs
+            .iter()
+            .any(|skip_dir| dir.starts_with(skip_dir))
+}
+
+#[derive(Debug, Clone)]
+pub struct MediaIndex {
+    pub path: PathBuf,
+    /// Indexed directory containing the file
+    pub root: PathBuf,
+    pub media_type: MediaType,
+    /// Lowercase extension of the file format
+    pub format: String,
+    /// Unique file stem in the output directory when flattening, see [`assign_flat_stems`]
+    pub flat_stem: Option<String>,
+    /// Options of the nearest [`DIRECTORY_CONFIG`], `None` uses the global options
+    pub options: Option<Arc<Options>>,
+}
+
+/// Filters applied while indexing
+#[derive(Debug)]
+pub struct IndexSettings {
+    pub max_depth: Option<u32>,
+    /// Paths to exclude, matched relative to the indexed directory
+    pub exclude: GlobSet,
+    /// Detect the media type by file content before the extension, files with an unknown extension
+    /// are always detected by content
+    pub detect_content: bool,
+    /// Canonical directories that are never indexed
+    pub skip_dirs: Vec<PathBuf>,
+    /// Index symlinked files and directories, symlinks are skipped otherwise
+    pub follow_symlinks: bool,
+    /// Skip files and directories whose name starts with a dot
+    pub ignore_hidden: bool,
+    /// Only index files modified after this time
+    pub since: Option<SystemTime>,
+    /// Global options that [`DIRECTORY_CONFIG`] files are merged on top of, `None` ignores them
+    pub options: Option<Arc<Options>>,
+}
+
+/// Configuration file that overrides the encoding settings of the files in its directory tree
+pub const DIRECTORY_CONFIG: &str = ".comva.toml";
+
+/// Indexes a directory or a single file, an explicit file is classified like an indexed file but
+/// isn't filtered
+pub fn index(directory: &Path, settings: &IndexSettings) -> Result<Vec<MediaIndex>> {
+    if directory.is_file() {
+        let root = directory.parent().unwrap_or(Path::new(""));
+        let item = classify_file(directory, root, settings.detect_content)
+            .ok_or_else(|| anyhow!("{directory:?} is not a supported media file."))?;
+        return Ok(vec![item]);
+    }
+    let visited = Mutex::new(HashSet::new());
+    let mut index_items = index_files(directory, directory, 0, settings, None, &visited)
+        .with_context(|| "Failed to index files")?;
+    index_items.sort_by(|a, b| a.media_type.cmp(&b.media_type));
+    Ok(index_items)
+}
+
+/// Indexes a directory, subdirectories are indexed in parallel
+///
+/// `options` are the options of the nearest directory config, `None` if there is none yet.
+fn index_files(
+    directory: &Path,
+    root: &Path,
+    depth: u32,
+    settings: &IndexSettings,
+    mut options: Option<Arc<Options>>,
+    visited: &Mutex<HashSet<PathBuf>>,
+) -> Result<Vec<MediaIndex>> {
+    let mut index = Vec::new();
+    // Skip directories that were already indexed to prevent symlink loops
+    let canonical = directory.canonicalize()?;
+    if settings.skip_dirs.contains(&canonical) || !insert_visited(visited, canonical) {
+        return Ok(index);
+    }
+    let config_path = directory.join(DIRECTORY_CONFIG);
+    if let (Some(global_options), true) = (&settings.options, config_path.is_file()) {
+        // Merged on top of the config of the parent directory
+        let mut directory_options = Options::clone(options.as_deref().unwrap_or(global_options));
+        directory_options
+            .apply_override(Config::load(&config_path)?)
+            .and_then(|_| directory_options.validate())
+            .with_context(|| format!("Invalid config file {config_path:?}."))?;
+        debug!("Using settings of {config_path:?}");
+        options = Some(Arc::new(directory_options));
+    }
+    let mut directories = Vec::new();
+    for file in fs::read_dir(directory)? {
+        let file = file?;
+        if !settings.follow_symlinks && file.file_type()?.is_symlink() {
+            continue;
+        }
+        if settings.ignore_hidden && file.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = file.path();
+        if let Ok(relative_path) = path.strip_prefix(root) {
+            if settings.exclude.is_match(relative_path) {
+                continue;
+            }
+        }
+        if path.is_file() {
+            // Files reachable through multiple symlinks are only indexed once
+            if settings.follow_symlinks && !insert_visited(visited, path.canonicalize()?) {
+                continue;
+            }
+            if !modified_since(&path, settings.since) {
+                continue;
+            }
+            if is_partial(&path) {
+                warn!("Skipping {path:?}, a partial output left behind by an interrupted run.");
+                continue;
+            }
+            if let Some(mut item) = classify_file(&path, root, settings.detect_content) {
+                item.options = options.clone();
+                index.push(item);
+            }
+        } else if path.is_dir() && settings.max_depth.is_none_or(|max| depth < max) {
+            directories.push(path);
+        }
+    }
+    let child_indexes = directories
+        .par_iter()
+        .map(|path| {
+            let options = options.clone();
+            index_files(path, root, depth + 1, settings, options, visited)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    index.extend(child_indexes.into_iter().flatten());
+    Ok(index)
+}
+
+/// Checks if a file was modified after `since`, files without a modification time are included
+fn modified_since(path: &Path, since: Option<SystemTime>) -> bool {
+    let Some(since) = since else {
+        return true;
+    };
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified >= since,
+        Err(_) => true,
+    }
+}
+
+/// Marks a path as visited, returns `false` if it was visited before
+fn insert_visited(visited: &Mutex<HashSet<PathBuf>>, path: PathBuf) -> bool {
+    visited
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(path)
+}
+
+/// Indexes the files listed in a file, one path per line, or stdin if the path is `-`
+pub fn index_list(list_path: &Path, settings: &IndexSettings) -> Result<Vec<MediaIndex>> {
+    let list = if list_path == Path::new("-") {
+        io::read_to_string(io::stdin()).with_context(|| "Failed to read file list from stdin.")?
+    } else {
+        fs::read_to_string(list_path)
+            .with_context(|| format!("Failed to read file list {list_path:?}."))?
+    };
+    // Paths are relative to the current directory
+    let root = env::current_dir()?.canonicalize()?;
+    let mut index = Vec::new();
+    for line in list.lines().filter(|line| !line.trim().is_empty()) {
+        let path = match Path::new(line).canonicalize() {
+            Ok(path) if path.is_file() => path,
+            _ => {
+                warn!("Skipping {line:?}, file not found.");
+                continue;
+            }
+        };
+        if !modified_since(&path, settings.since) {
+            continue;
+        }
+        match classify_file(&path, &root, settings.detect_content) {
+            Some(item) => index.push(item),
+            None => warn!("Skipping {line:?}, not a known media file."),
+        }
+    }
+    Ok(index)
+}
+
+/// Classifies a file by its extension, or by its content first if `detect_content` is set or the
+/// extension is unknown
+fn classify_file(path: &Path, root: &Path, detect_content: bool) -> Option<MediaIndex> {
+    // Backups and statistics of earlier runs would be detected by their content
+    if is_artifact(path) {
+        return None;
+    }
+    let media_type = classify(path);
+    // Raw camera images are TIFF based, detecting them by content would lose the raw format
+    let is_raw = path
+        .extension()
+        .is_some_and(|ext| RAW_FORMATS.contains(&ext.to_string_lossy().to_lowercase().as_str()));
+    if (detect_content && !is_raw) || media_type.is_none() {
+        if let Some((media_type, format)) = detect_format(path) {
+            return Some(MediaIndex {
+                path: path.to_path_buf(),
+                root: root.to_path_buf(),
+                media_type,
+                format,
+                flat_stem: None,
+                options: None,
+            });
+        }
+    }
+    let media_type = media_type?;
+    let format = path.extension()?.to_string_lossy().to_lowercase();
+    Some(MediaIndex {
+        path: path.to_path_buf(),
+        root: root.to_path_buf(),
+        media_type,
+        format,
+        flat_stem: None,
+        options: None,
+    })
+}
+
+/// Media type and format of a file by its content, formats that are not in the
+/// [`default_extensions`] are not detected
+fn detect_format(path: &Path) -> Option<(MediaType, String)> {
+    let kind = infer::get_from_path(path).ok().flatten()?;
+    let media_type = EXTENSIONS.get(kind.extension())?.clone();
+    Some((media_type, kind.extension().to_string()))
+}
+
+/// Whether a file was written by an earlier run, such as a partial output, a backup, a thumbnail or
+/// the statistics of a two-pass encode
+fn is_artifact(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let is_thumbnail = path
+        .file_stem()
+        .is_some_and(|stem| stem.to_string_lossy().ends_with(".thumb"));
+    is_partial(path) || is_thumbnail || name.ends_with(".backup") || name.contains(".passlog")
+}
+
+/// Outcome of a single file compression
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileOutcome {
+    Compressed,
+    /// Would be compressed, used in dry runs
+    Planned,
+    Skipped,
+    /// Original was kept because the output did not save enough space
+    Kept,
+    /// Skipped because the file is smaller than the minimum size
+    TooSmall,
+    Failed,
+}
+
+/// Result of a single processed file, as written to the report
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    pub source_path: PathBuf,
+    pub media_type: MediaType,
+    pub output_path: PathBuf,
+    pub original_size: Option<u64>,
+    pub output_size: Option<u64>,
+    pub status: FileOutcome,
+    pub error: Option<String>,
+    /// Processing time in seconds
+    pub duration: Option<f64>,
+}
+
+impl FileResult {
+    fn failed(mut self, error: String) -> Self {
+        self.status = FileOutcome::Failed;
+        self.error = Some(error);
+        self
+    }
+}
+
+/// Groups the results of the outputs of each file, as returned by [`compress`]
+pub fn group_by_source(results: &[FileResult]) -> impl Iterator<Item = &[FileResult]> {
+    results.chunk_by(|a, b| a.source_path == b.source_path)
+}
+
+/// Original size and total size of the compressed outputs of a file, from the results of all its
+/// outputs
+///
+/// The original is only counted once. Without a compressed output the smallest output that was
+/// discarded is used, so files that grew can be reported.
+pub fn source_sizes(outputs: &[FileResult]) -> Option<(u64, u64)> {
+    let original_size = outputs.iter().find_map(|result| result.original_size)?;
+    let compressed: Vec<u64> = outputs
+        .iter()
+        .filter(|result| result.status == FileOutcome::Compressed)
+        .filter_map(|result| result.output_size)
+        .collect();
+    let output_size = match compressed.is_empty() {
+        true => outputs
+            .iter()
+            .filter(|result| result.status == FileOutcome::Kept)
+            .filter_map(|result| result.output_size)
+            .min()?,
+        false => compressed.iter().sum(),
+    };
+    Some((original_size, output_size))
+}
+
+/// Splits the files of each directory into at most `limit` chunks, the files keep their order and
+/// the directories are in the order of their first file
+fn group_by_directory(files: Vec<MediaIndex>, limit: usize) -> Vec<Vec<MediaIndex>> {
+    // Position of each directory in `directories`
+    let mut positions: HashMap<PathBuf, usize> = HashMap::new();
+    let mut directories: Vec<Vec<MediaIndex>> = Vec::new();
+    for file in files {
+        let directory = file.path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let position = *positions.entry(directory).or_insert_with(|| {
+            directories.push(Vec::new());
+            directories.len() - 1
+        });
+        directories[position].push(file);
+    }
+    let mut chunks = Vec::new();
+    for files in directories {
+        let chunk_count = limit.min(files.len());
+        let mut directory_chunks: Vec<Vec<MediaIndex>> = vec![Vec::new(); chunk_count];
+        for (i, file) in files.into_iter().enumerate() {
+            directory_chunks[i % chunk_count].push(file);
+        }
+        chunks.extend(directory_chunks);
+    }
+    chunks
+}
+
+/// Files processed in previous runs, stored as one JSON path per line so interrupted runs can resume
+pub struct Ledger {
+    done: HashSet<PathBuf>,
+    /// Not opened in dry runs
+    file: Option<Mutex<fs::File>>,
+}
+
+impl Ledger {
+    pub fn open(path: &Path, dry_run: bool) -> Result<Self> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read state file {path:?}."))
+            }
+        };
+        // Ignores lines that are incomplete because of an interrupted write
+        let done = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect();
+        let file = if dry_run {
+            None
+        } else {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open state file {path:?}."))?;
+            Some(Mutex::new(file))
+        };
+        Ok(Ledger { done, file })
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.done.contains(path)
+    }
+
+    fn record(&self, path: &Path) -> Result<()> {
+        if let Some(file) = &self.file {
+            let mut file = file
+                .lock()
+                .map_err(|_| anyhow!("State file lock poisoned"))?;
+            writeln!(file, "{}", serde_json::to_string(path)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cancellation of a run, shared with e.g. a Ctrl-C handler
+#[derive(Debug, Default)]
+pub struct Cancellation {
+    /// No new jobs are started once set, running jobs still finish
+    pub stop: AtomicBool,
+    /// Running FFMPEG commands are killed too and their partial outputs removed, set it together
+    /// with `stop`
+    pub abort: AtomicBool,
+}
+
+impl Cancellation {
+    pub const fn new() -> Self {
+        Cancellation {
+            stop: AtomicBool::new(false),
+            abort: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Compresses all files in the index, returns the result of every processed file
+///
+/// Files that are not processed because their media type isn't compressed, or because the run was
+/// stopped, have no result. The run can be stopped or aborted through `cancel`, e.g. by a Ctrl-C
+/// handler.
+/// Progress is reported to `on_event` from the worker threads. ImageMagick must be initialized with
+/// [`init_imagemagick`] first.
+pub fn compress(
+    index: Vec<MediaIndex>,
+    options: &Options,
+    cancel: &Cancellation,
+    ledger: Option<&Ledger>,
+    on_event: &(dyn Fn(ProgressEvent) + Sync),
+) -> Result<Vec<FileResult>> {
+    // The index is sorted by media type
+    let counts: Vec<String> = index
+        .chunk_by(|a, b| a.media_type == b.media_type)
+        .map(|group| {
+            let name = match (&group[0].media_type, group.len()) {
+                (MediaType::Image, 1) => "image",
+                (MediaType::Image, _) => "images",
+                (MediaType::Audio, _) => "audio",
+                (MediaType::Video, 1) => "video",
+                (MediaType::Video, _) => "videos",
+            };
+            format!("{} {name}", group.len())
+        })
+        .collect();
+    if counts.is_empty() {
+        info!("Starting compression of 0 files..");
+    } else {
+        info!(
+            "Starting compression of {} files ({})..",
+            index.len(),
+            counts.join(", ")
+        );
+    }
+
+    let planned = plan_outputs(&index, options);
+    let start = Instant::now();
+    on_event(ProgressEvent::Started { total: index.len() });
+    // Bytes saved so far, used to stop once the saved budget is reached
+    let saved = AtomicU64::new(0);
+    let budget_reached = || {
+        options
+            .stop_after_saved
+            .is_some_and(|budget| saved.load(Ordering::SeqCst) >= budget)
+    };
+    // Set when the disk is nearly full, separate from `cancel` so a Ctrl-C still aborts
+    let disk_full = AtomicBool::new(false);
+    // Don't start new jobs after Ctrl-C, once enough space is saved or when the disk is nearly full
+    let stopped = || {
+        cancel.stop.load(Ordering::SeqCst) || disk_full.load(Ordering::SeqCst) || budget_reached()
+    };
+    let compress_one = |file: MediaIndex| -> Vec<FileResult> {
+        if stopped() {
+            return Vec::new();
+        }
+        if let (Some(min_free_space), false) = (options.min_free_space, options.dry_run) {
+            let dir = output_target(&file, options)
+                .and_then(|(output_path, _)| output_path.parent().map(Path::to_path_buf))
+                .unwrap_or_default();
+            match free_space(&dir) {
+                Some(free) if free < min_free_space => {
+                    // Running jobs can still finish, their outputs are usually smaller
+                    if !disk_full.swap(true, Ordering::SeqCst) {
+                        warn!(
+                            "Only {} of disk space is free, stopping after the running jobs finish..",
+                            format_size(free)
+                        );
+                    }
+                    return Vec::new();
+                }
+                Some(_) => {}
+                None => debug!("Failed to check the free disk space of {dir:?}"),
+            }
+        }
+        let file_options = file.options.clone();
+        let file_options = file_options.as_deref().unwrap_or(options);
+        let outputs = planned.get(&file.path).cloned().unwrap_or_default();
+        let results = process_file(file, outputs, file_options, cancel, on_event);
+        let compressed = results
+            .iter()
+            .any(|result| result.status == FileOutcome::Compressed);
+        if let (true, Some((original_size, output_size))) = (compressed, source_sizes(&results)) {
+            let file_saved = original_size.saturating_sub(output_size);
+            let before = saved.fetch_add(file_saved, Ordering::SeqCst);
+            if let Some(budget) = options.stop_after_saved {
+                if before < budget && before + file_saved >= budget {
+                    info!(
+                        "Saved {}, stopping after the running jobs finish..",
+                        format_size(before + file_saved)
+                    );
+                }
+            }
+        }
+        for result in results.iter() {
+            on_event(ProgressEvent::finished_file(result));
+        }
+        // The outputs of a file fail together, so the error is only shown once
+        if let Some(result) = results.first() {
+            if let Some(error) = &result.error {
+                error!("Compression of {:?} failed:\n{error}", result.source_path);
+            }
+            let processed = results
+                .iter()
+                .any(|result| matches!(result.status, FileOutcome::Compressed | FileOutcome::Kept));
+            if let (Some(ledger), true) = (ledger, processed) {
+                if let Err(err) = ledger.record(&result.source_path) {
+                    error!("Failed to update state file: {err:#}");
+                }
+            }
+        }
+        results
+    };
+    // Shared by the image and other workers, so there are never more IO threads than requested
+    let io_pool = match (options.concurrency_per_directory, options.threads_io) {
+        (None, Some(io_threads)) if io_threads > 0 => {
+            Some(ThreadPoolBuilder::new().num_threads(io_threads).build()?)
+        }
+        _ => None,
+    };
+    let compress_files = |files: Vec<MediaIndex>| -> Vec<FileResult> {
+        match (options.concurrency_per_directory, &io_pool) {
+            // Each chunk is compressed sequentially, limiting the jobs per directory
+            (Some(limit), _) => {
+                let chunks = group_by_directory(files, limit);
+                let compress_chunk =
+                    |chunk: Vec<MediaIndex>| chunk.into_iter().flat_map(compress_one);
+                // Workers take the chunks in order when sorted, like the files below
+                if options.sort_by.is_some() {
+                    chunks
+                        .into_iter()
+                        .par_bridge()
+                        .flat_map_iter(compress_chunk)
+                        .collect()
+                } else {
+                    chunks
+                        .into_par_iter()
+                        .flat_map_iter(compress_chunk)
+                        .collect()
+                }
+            }
+            (None, Some(io_pool)) => {
+                // The IO threads only stay a few files ahead of the workers
+                let ahead = io_pool.current_num_threads() * 2;
+                let paths: Vec<PathBuf> = files.iter().map(|file| file.path.clone()).collect();
+                io_pool.in_place_scope(|scope| {
+                    let read_ahead = |i: usize| {
+                        if let Some(path) = paths.get(i) {
+                            scope.spawn(move |_| {
+                                if !stopped() {
+                                    prefetch(path);
+                                }
+                            });
+                        }
+                    };
+                    (0..ahead).for_each(read_ahead);
+                    files
+                        .into_iter()
+                        .enumerate()
+                        .par_bridge()
+                        .flat_map_iter(|(i, file)| {
+                            read_ahead(i + ahead);
+                            compress_one(file)
+                        })
+                        .collect()
+                })
+            }
+            // Workers take the files in order, splitting the list would start in several places
+            _ if options.sort_by.is_some() => files
+                .into_iter()
+                .par_bridge()
+                .flat_map_iter(compress_one)
+                .collect(),
+            _ => files.into_par_iter().flat_map_iter(compress_one).collect(),
+        }
+    };
+    let threads = options.threads();
+    debug!("Using {threads} worker threads");
+    if let (Some(io_threads), None) = (options.threads_io, options.concurrency_per_directory) {
+        debug!("Using {io_threads} IO threads to read files ahead");
+    }
+    let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+    let results = match options.image_threads {
+        Some(image_threads) => {
+            // Images are compressed in a separate pool to limit memory usage
+            let image_pool = ThreadPoolBuilder::new()
+                .num_threads(image_threads)
+                .build()?;
+            let (images, others): (Vec<_>, Vec<_>) = index
+                .into_iter()
+                .partition(|file| file.media_type == MediaType::Image);
+            let (image_results, other_results) = thread::scope(|scope| {
+                let image_worker = scope.spawn(|| image_pool.install(|| compress_files(images)));
+                let other_results = pool.install(|| compress_files(others));
+                (image_worker.join(), other_results)
+            });
+            let mut results = image_results.map_err(|_| anyhow!("Image worker panicked."))?;
+            results.extend(other_results);
+            results
+        }
+        None => pool.install(|| compress_files(index)),
+    };
+    if cancel.stop.load(Ordering::SeqCst) || disk_full.load(Ordering::SeqCst) {
+        warn!("Stopped before all files were processed.");
+    }
+    let failed = group_by_source(&results)
+        .filter(|outputs| {
+            outputs
+                .iter()
+                .any(|result| result.status == FileOutcome::Failed)
+        })
+        .count();
+    on_event(ProgressEvent::Finished {
+        processed: group_by_source(&results).count(),
+        failed,
+        saved: saved.load(Ordering::SeqCst),
+        elapsed: start.elapsed().as_secs_f64(),
+    });
+
+    Ok(results)
+}
+
+/// Files are read ahead up to this size, larger files are only partially cached
+const PREFETCH_LIMIT: u64 = 512 << 20;
+
+/// Reads a file so it's in the page cache once a worker picks it up
+fn prefetch(path: &Path) {
+    let result = fs::File::open(path)
+        .and_then(|file| io::copy(&mut file.take(PREFETCH_LIMIT), &mut io::sink()));
+    if let Err(err) = result {
+        // The worker reports the error when it reads the file
+        debug!("Failed to read {path:?} ahead: {err}");
+    }
+}
+
+/// Adds a _1, _2.. suffix to the file stem, the first number that isn't an existing file or taken
+/// otherwise is used
+fn numbered_path(path: &Path, taken: impl Fn(&Path) -> bool) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{stem}_{n}{ext}")))
+        .find(|path| !path.exists() && !taken(path))
+        .unwrap()
+}
+
+/// Appends a suffix to the file name, adding a number if the path already exists
+fn unique_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".{suffix}"));
+    let mut unique = path.with_file_name(&file_name);
+    let mut n = 1;
+    while unique.exists() {
+        let mut numbered = path.file_name().unwrap_or_default().to_os_string();
+        numbered.push(format!(".{n}.{suffix}"));
+        unique = path.with_file_name(numbered);
+        n += 1;
+    }
+    unique
+}
+
+/// Disk space available in bytes on the file system of a path, which doesn't have to exist yet
+fn free_space(path: &Path) -> Option<u64> {
+    // The nearest existing ancestor is on the same file system as the output will be
+    let existing = path.ancestors().find(|path| path.exists())?;
+    #[cfg(unix)]
+    {
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+        let path = CString::new(existing.as_os_str().as_bytes()).ok()?;
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = existing;
+        None
+    }
+}
+
+/// Formats a size in bytes using binary units
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.0} {}", UNITS[unit])
+    }
+}
+
+/// Progress event passed to the callback of [`compress`], printed by `--progress-format jsonl`
+///
+/// Every event is an object with an `event` field holding the kebab-case name of the event.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum ProgressEvent {
+    /// Compression started, `total` is the amount of indexed files
+    Started { total: usize },
+    /// A file is being processed
+    FileBegin {
+        path: PathBuf,
+        media_type: MediaType,
+        output_path: PathBuf,
+    },
+    /// A file was processed, including skipped and kept files
+    FileDone {
+        path: PathBuf,
+        output_path: PathBuf,
+        status: FileOutcome,
+        original_size: Option<u64>,
+        output_size: Option<u64>,
+        /// Processing time in seconds
+        duration: Option<f64>,
+    },
+    /// Processing a file failed
+    FileFailed {
+        path: PathBuf,
+        error: String,
+        /// Processing time in seconds
+        duration: Option<f64>,
+    },
+    /// All files were processed or the run was stopped
+    Finished {
+        /// Amount of processed files, a file with several outputs counts once
+        processed: usize,
+        failed: usize,
+        /// Bytes saved by the compressed files
+        saved: u64,
+        /// Time since the start in seconds
+        elapsed: f64,
+    },
+}
+
+impl ProgressEvent {
+    fn finished_file(result: &FileResult) -> Self {
+        match &result.error {
+            Some(error) => ProgressEvent::FileFailed {
+                path: result.source_path.clone(),
+                error: error.clone(),
+                duration: result.duration,
+            },
+            None => ProgressEvent::FileDone {
+                path: result.source_path.clone(),
+                output_path: result.output_path.clone(),
+                status: result.status,
+                original_size: result.original_size,
+                output_size: result.output_size,
+                duration: result.duration,
+            },
+        }
+    }
+}
+
+/// Output of a file in a run, see [`plan_outputs`]
+#[derive(Debug, Clone)]
+struct PlannedOutput {
+    path: PathBuf,
+    ext: String,
+    /// Another file of the run that already uses the path, the output is skipped
+    conflict: Option<PathBuf>,
+}
+
+/// Decides the outputs of all files before any is written, so files with the same output such as
+/// song.wav and song.flac can't replace each other's output or original
+///
+/// The first file in index order keeps the path, the others are renamed with the rename policy and
+/// skipped otherwise. The overwrite policy only replaces files that existed before the run.
+fn plan_outputs(index: &[MediaIndex], options: &Options) -> HashMap<PathBuf, Vec<PlannedOutput>> {
+    // Compared case-insensitively because of case-insensitive file systems
+    let key = |path: &Path| path.to_string_lossy().to_lowercase();
+    let file_options = |i: usize| index[i].options.as_deref().unwrap_or(options);
+    let targets: Vec<Vec<(PathBuf, String)>> = index
+        .iter()
+        .enumerate()
+        .map(|(i, file)| output_targets(file, file_options(i)))
+        .collect();
+    // Index of the file using each path, the compressed originals are in use from the start
+    let mut claimed: HashMap<String, usize> = HashMap::new();
+    for (i, file) in index.iter().enumerate() {
+        if !targets[i].is_empty() {
+            claimed.entry(key(&file.path)).or_insert(i);
+        }
+    }
+    let mut planned = HashMap::new();
+    for (i, (file, targets)) in index.iter().zip(targets).enumerate() {
+        let mut outputs = Vec::new();
+        for (mut path, ext) in targets {
+            let mut owner = claimed
+                .get(&key(&path))
+                .copied()
+                .filter(|&owner| owner != i);
+            let exists = path.exists() && !is_same_path(&file.path, &path);
+            if file_options(i).overwrite_policy == OverwritePolicy::Rename
+                && (owner.is_some() || exists)
+            {
+                path = numbered_path(&path, |path| claimed.contains_key(&key(path)));
+                owner = None;
+            }
+            if owner.is_none() {
+                claimed.insert(key(&path), i);
+            }
+            outputs.push(PlannedOutput {
+                path,
+                ext,
+                conflict: owner.map(|owner| index[owner].path.clone()),
+            });
+        }
+        planned.insert(file.path.clone(), outputs);
+    }
+    planned
+}
+
+/// Returns the output path and extension of a file, or `None` if its media type is not compressed
+///
+/// Images with several output formats return the first one.
+pub fn output_target(file: &MediaIndex, options: &Options) -> Option<(PathBuf, String)> {
+    output_targets(file, options).into_iter().next()
+}
+
+/// Returns the output paths and extensions of a file, empty if its media type is not compressed
+pub fn output_targets(file: &MediaIndex, options: &Options) -> Vec<(PathBuf, String)> {
+    if let Some((_, to)) = options
+        .format_map
+        .iter()
+        .find(|(from, _)| canonical_format(from) == canonical_format(&file.format))
+    {
+        return vec![(output_path(file, options, to), to.clone())];
+    }
+    let format_flags = match file.media_type {
+        MediaType::Image => match &options.image_ext {
+            Some(exts) if exts.is_empty() => vec![None],
+            Some(exts) => exts.iter().cloned().map(Some).collect(),
+            None => Vec::new(),
+        },
+        MediaType::Audio => options.audio_ext.clone().into_iter().collect(),
+        MediaType::Video => options.video_ext.clone().into_iter().collect(),
+    };
+    let mut targets: Vec<(PathBuf, String)> = Vec::new();
+    for format_flag in format_flags {
+        // Files without an extension, e.g. detected by content, use the extension of the detected format
+        let output_ext = format_flag
+            .map(|ext| ext.trim_start_matches('.').to_string())
+            .filter(|ext| !ext.is_empty())
+            .unwrap_or_else(|| match RAW_FORMATS.contains(&file.format.as_str()) {
+                // Raw camera images can only be read
+                true => "jpg".to_string(),
+                false => file.format.clone(),
+            });
+        // The same format given twice is only written once
+        if !targets
+            .iter()
+            .any(|(_, ext)| ext.eq_ignore_ascii_case(&output_ext))
+        {
+            targets.push((output_path(file, options, &output_ext), output_ext));
+        }
+    }
+    targets
+}
+
+/// Output path of a file, inside the output directory if one is set
+fn output_path(file: &MediaIndex, options: &Options, output_ext: &str) -> PathBuf {
+    let path = match &options.output_dir {
+        Some(output_dir) => {
+            if let (true, Some(stem)) = (options.flatten, &file.flat_stem) {
+                // The stem has no extension, so one is added instead of replacing part of the stem
+                let path = output_dir.join(format!("{stem}.ext"));
+                return with_output_ext(file, options, path, output_ext);
+            }
+            mirrored_path(&file.path, &file.root, output_dir)
+        }
+        None => file.path.clone(),
+    };
+    with_output_ext(file, options, path, output_ext)
+}
+
+/// Path of a file inside another directory, mirroring its path relative to the indexed directory
+///
+/// Files outside of the indexed directory are placed directly in the directory.
+fn mirrored_path(path: &Path, root: &Path, dir: &Path) -> PathBuf {
+    let relative_path = path
+        .strip_prefix(root)
+        .unwrap_or_else(|_| Path::new(path.file_name().unwrap_or_default()));
+    dir.join(relative_path)
+}
+
+/// Replaces the extension of an output path
+fn with_output_ext(
+    file: &MediaIndex,
+    options: &Options,
+    path: PathBuf,
+    output_ext: &str,
+) -> PathBuf {
+    // The source extension is kept when it's the same format, so photo.jpeg isn't renamed to photo.jpg
+    let source_ext = file
+        .path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .filter(|ext| canonical_format(ext) == canonical_format(output_ext))
+        .unwrap_or_else(|| output_ext.to_string());
+    if options.lowercase_ext {
+        path.with_extension(source_ext.to_lowercase())
+    } else {
+        path.with_extension(source_ext)
+    }
+}
+
+/// Sorts the index by media type, and within a media type by the sort key if there is one
+pub fn sort_index(index: &mut [MediaIndex], sort_by: Option<SortBy>) {
+    let Some(sort_by) = sort_by else {
+        index.sort_by(|a, b| a.media_type.cmp(&b.media_type));
+        return;
+    };
+    index.sort_by_cached_key(|file| {
+        let metadata = fs::metadata(&file.path).ok();
+        let size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+        // Seconds since the epoch, so descending order can negate it
+        let mtime = metadata
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |age| age.as_secs() as i64);
+        let key = match sort_by {
+            SortBy::Size => size as i64,
+            SortBy::SizeDesc => -(size as i64),
+            SortBy::Mtime => mtime,
+            SortBy::MtimeDesc => -mtime,
+        };
+        (file.media_type.clone(), key)
+    });
+}
+
+/// Gives every file a unique stem in the flattened output directory, files with the same name in
+/// different directories get a _1, _2.. suffix in path order so the names are the same every run
+pub fn assign_flat_stems(index: &mut [MediaIndex]) {
+    let mut order: Vec<usize> = (0..index.len()).collect();
+    order.sort_by(|&a, &b| index[a].path.cmp(&index[b].path));
+    let mut taken = HashSet::new();
+    for i in order {
+        let stem = index[i]
+            .path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        // Compared case-insensitively because of case-insensitive file systems
+        let mut unique = stem.clone();
+        let mut n = 1;
+        while !taken.insert(unique.to_lowercase()) {
+            unique = format!("{stem}_{n}");
+            n += 1;
+        }
+        index[i].flat_stem = Some(unique);
+    }
+}
+
+/// Lowercase name of a format, with aliases such as jpeg and jpg resolved to one name
+fn canonical_format(ext: &str) -> String {
+    let ext = ext.to_lowercase();
+    match ext.as_str() {
+        "jpeg" => "jpg".to_string(),
+        "tif" => "tiff".to_string(),
+        _ => ext,
+    }
+}
+
+/// Checks if the output replaces the source, also when only the casing differs, e.g. with lowercase
+/// extensions, unless another file already has the name of the output
+pub fn is_same_path(source_path: &Path, output_path: &Path) -> bool {
+    if source_path == output_path {
+        return true;
+    }
+    let source_str = source_path.to_string_lossy();
+    if !source_str.eq_ignore_ascii_case(&output_path.to_string_lossy()) {
+        return false;
+    }
+    // On case-insensitive file systems both names refer to the original
+    !output_path.exists() || is_same_file(source_path, output_path)
+}
+
+/// Checks if two existing paths refer to the same file
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+    }
+}
+
+/// Compresses a single file and collects the outcome of each output, empty if its media type is not
+/// compressed
+fn process_file(
+    file: MediaIndex,
+    outputs: Vec<PlannedOutput>,
+    options: &Options,
+    cancel: &Cancellation,
+    on_event: &(dyn Fn(ProgressEvent) + Sync),
+) -> Vec<FileResult> {
+    let mut results: Vec<FileResult> = outputs
+        .iter()
+        .map(
+            |PlannedOutput {
+                 path: output_path, ..
+             }| {
+                on_event(ProgressEvent::FileBegin {
+                    path: file.path.clone(),
+                    media_type: file.media_type.clone(),
+                    output_path: output_path.clone(),
+                });
+                FileResult {
+                    source_path: file.path.clone(),
+                    media_type: file.media_type.clone(),
+                    output_path: output_path.clone(),
+                    original_size: None,
+                    output_size: None,
+                    status: FileOutcome::Skipped,
+                    error: None,
+                    duration: None,
+                }
+            },
+        )
+        .collect();
+    let start = Instant::now();
+    if let Err(err) = compress_file(&mut results, &file.root, &outputs, options, cancel) {
+        let error = format!("{err:#}");
+        results = results
+            .into_iter()
+            .map(|result| result.failed(error.clone()))
+            .collect();
+    }
+    if !options.dry_run {
+        for result in results.iter_mut() {
+            result.duration = Some(start.elapsed().as_secs_f64());
+        }
+    }
+    results
+}
+
+/// Compresses a file to each of its outputs, the status of every output is set in its result
+///
+/// The original is only removed once all outputs are compressed.
+fn compress_file(
+    results: &mut [FileResult],
+    root: &Path,
+    outputs: &[PlannedOutput],
+    options: &Options,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let Some(first) = results.first() else {
+        return Ok(());
+    };
+    let source_path = first.source_path.clone();
+    let media_type = first.media_type.clone();
+
+    let metadata = fs::metadata(&source_path).with_context(|| "Failed to read metadata")?;
+    let original_size = metadata.len();
+    for result in results.iter_mut() {
+        result.original_size = Some(original_size);
+    }
+    if options
+        .min_size
+        .is_some_and(|min_size| original_size < min_size)
+    {
+        for result in results.iter_mut() {
+            result.status = FileOutcome::TooSmall;
+        }
+        return Ok(());
+    }
+
+    // Animated images are converted to videos with FFMPEG, still images can't be
+    let is_animation = |output_ext: &str| {
+        media_type == MediaType::Image && ANIMATION_FORMATS.contains(&output_ext)
+    };
+    // Indices of the outputs that are written, the others are skipped
+    let mut targets = Vec::new();
+    for (i, result) in results.iter_mut().enumerate() {
+        if let Some(other) = &outputs[i].conflict {
+            if options.dry_run {
+                warn!(
+                    "Would skip {source_path:?}, its output {:?} is also used by {other:?}",
+                    result.output_path
+                );
+            } else {
+                warn!(
+                    "Skipped {source_path:?}, its output {:?} is also used by {other:?}",
+                    result.output_path
+                );
+            }
+            continue;
+        }
+        let overwritten = is_same_path(&source_path, &result.output_path);
+        if !overwritten && result.output_path.exists() {
+            match options.overwrite_policy {
+                OverwritePolicy::Skip => {
+                    if options.dry_run {
+                        info!("Would skip {source_path:?}, output already exists!");
+                    } else {
+                        info!("Skipped {source_path:?}, output already exists!");
+                    }
+                    continue;
+                }
+                OverwritePolicy::Overwrite => {
+                    debug!("Replacing existing output {:?}", result.output_path)
+                }
+                OverwritePolicy::Rename => {
+                    result.output_path = numbered_path(&result.output_path, |_| false);
+                }
+            }
+        }
+        // Dry runs don't read the image, so whether it's animated is unknown
+        if is_animation(&outputs[i].ext) && !options.dry_run && image_frames(&source_path)? < 2 {
+            info!("Skipped {source_path:?}, not an animated image");
+            continue;
+        }
+        targets.push(i);
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    // The original is only changed when it's replaced or removed
+    let overwritten = targets
+        .iter()
+        .any(|&i| is_same_path(&source_path, &results[i].output_path));
+    let modifies_original = overwritten || !options.keep_files;
+    if let Some(reason) = readonly_reason(&source_path).filter(|_| modifies_original) {
+        if !options.force {
+            warn!("Skipped {source_path:?}, {reason}, use --force to compress it anyway");
+            return Ok(());
+        }
+        debug!("Compressing {source_path:?} even though {reason}");
+    }
+
+    if options.dry_run {
+        for &i in targets.iter() {
+            let result = &mut results[i];
+            let action = match (
+                is_same_path(&source_path, &result.output_path),
+                overwritten,
+                options.keep_files,
+            ) {
+                (true, _, true) => "overwrite original, keep backup",
+                (true, _, false) => "overwrite original",
+                // Another output of the file replaces the original
+                (false, true, _) => "original is overwritten",
+                (false, false, true) => "keep original",
+                (false, false, false) => "remove original",
+            };
+            let animated = match is_animation(&outputs[i].ext) {
+                true => ", animated: unknown",
+                false => "",
+            };
+            info!(
+                "Would compress {source_path:?} to {:?} ({action}{animated})",
+                result.output_path
+            );
+            result.status = FileOutcome::Planned;
+        }
+        return Ok(());
+    }
+
+    // Kept until the function returns, which restores the permissions
+    let _writable_dir = match source_path.parent() {
+        Some(dir) if options.force && modifies_original && is_readonly(dir) => {
+            Some(WritableDir::new(dir)?)
+        }
+        _ => None,
+    };
+
+    // The outputs are written to partial files and only replace anything once they're complete, so
+    // the original stays intact until the outputs are in place
+    let mut partials = Vec::new();
+    for &i in targets.iter() {
+        let output_path = &results[i].output_path;
+        if let Some(parent) = output_path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create output directory {parent:?}"))?;
+        }
+        info!("Compressing {output_path:?}..");
+        match partial_path(output_path) {
+            Ok(partial_path) => partials.push((i, partial_path)),
+            Err(err) => {
+                for (_, partial_path) in partials.iter() {
+                    fs::remove_file(partial_path).unwrap_or_default();
+                }
+                return Err(err);
+            }
+        }
+    }
+    let discard_partials = || {
+        for (_, partial_path) in partials.iter() {
+            fs::remove_file(partial_path).unwrap_or_default();
+        }
+    };
+    let start = Instant::now();
+
+    // Still images are decoded once and written to each of their outputs
+    let (images, others): (Vec<_>, Vec<_>) = partials
+        .iter()
+        .partition(|(i, _)| media_type == MediaType::Image && !is_animation(&outputs[*i].ext));
+    // Cleared when an image doesn't reach the minimum SSIM
+    let mut quality_met = vec![true; results.len()];
+    let mut compress_result = Ok(());
+    if !images.is_empty() {
+        let outputs: Vec<(PathBuf, Option<PathBuf>)> = images
+            .iter()
+            .map(|(i, partial_path)| {
+                let thumbnail_path = options
+                    .thumbnail
+                    .and_then(|_| thumbnail_path(&results[*i].output_path));
+                (partial_path.clone(), thumbnail_path)
+            })
+            .collect();
+        compress_result = compress_image(&source_path, &outputs, options).map(|met| {
+            for ((i, _), met) in images.iter().zip(met) {
+                quality_met[*i] = met;
+            }
+        });
+    }
+    for (i, partial_path) in others {
+        if compress_result.is_err() {
+            break;
+        }
+        compress_result = compress_ffmpeg(
+            &source_path,
+            partial_path,
+            &outputs[*i].ext,
+            &media_type,
+            options,
+            cancel,
+        );
+    }
+    if let Err(err) = compress_result {
+        // Remove the incomplete outputs, the original was never touched
+        discard_partials();
+        return Err(err);
+    }
+
+    // Outputs that are smaller than the original, which are moved into place
+    let mut compressed = Vec::new();
+    for (i, partial_path) in partials.iter() {
+        let result = &mut results[*i];
+        let output_path = &result.output_path;
+        if !quality_met[*i] {
+            info!("Kept original {source_path:?}, quality of {output_path:?} is below the minimum SSIM");
+            fs::remove_file(partial_path).with_context(|| "Failed to remove output file")?;
+            result.status = FileOutcome::Kept;
+            continue;
+        }
+        let decoded_by_ffmpeg = media_type != MediaType::Image || is_animation(&outputs[*i].ext);
+        let output_result = finish_output(
+            partial_path,
+            output_path,
+            decoded_by_ffmpeg,
+            &metadata,
+            options,
+            cancel,
+        );
+        let output_size = match output_result {
+            Ok(output_size) => output_size,
+            Err(err) => {
+                discard_partials();
+                return Err(err);
+            }
+        };
+        result.output_size = Some(output_size);
+
+        let savings = (1.0 - output_size as f64 / original_size as f64) * 100.0;
+        // Copying streams doesn't save space, the new container is the point
+        let remuxed = options.copy_streams && media_type != MediaType::Image;
+        if !remuxed && (output_size > original_size || savings < options.min_savings) {
+            info!("Kept original {source_path:?}, no savings for {output_path:?} ({savings:.1}%)");
+            fs::remove_file(partial_path).with_context(|| "Failed to remove output file")?;
+            result.status = FileOutcome::Kept;
+            continue;
+        }
+        compressed.push((*i, partial_path));
+    }
+    debug!("Compressed {source_path:?} in {:.2?}", start.elapsed());
+
+    // The output that replaces the original is moved into place last
+    compressed.sort_by_key(|(i, _)| is_same_path(&source_path, &results[*i].output_path));
+    for (i, partial_path) in compressed {
+        let output_path = &results[i].output_path;
+        if is_same_path(&source_path, output_path) {
+            if let Err(err) =
+                replace_original(&source_path, partial_path, output_path, root, options)
+            {
+                discard_partials();
+                return Err(err);
+            }
+        } else if let Err(err) = commit_output(partial_path, output_path) {
+            discard_partials();
+            return Err(err);
+        }
+        results[i].status = FileOutcome::Compressed;
+    }
+    // The original is removed last, once every output is in place
+    let all_compressed = results
+        .iter()
+        .all(|result| result.status == FileOutcome::Compressed);
+    if !overwritten && !options.keep_files && all_compressed {
+        fs::remove_file(&source_path).with_context(|| "Failed to remove original file")?;
+    }
+    Ok(())
+}
+
+/// Verifies a written output and copies the timestamps and permissions of the original to it, returns
+/// the size of the output
+fn finish_output(
+    partial_path: &Path,
+    output_path: &Path,
+    decoded_by_ffmpeg: bool,
+    metadata: &fs::Metadata,
+    options: &Options,
+    cancel: &Cancellation,
+) -> Result<u64> {
+    // The output is discarded if it is broken
+    if options.verify {
+        verify_output(partial_path, decoded_by_ffmpeg, options.timeout(), cancel)
+            .with_context(|| format!("Verification of {output_path:?} failed"))?;
+    }
+    if !options.no_preserve_timestamps {
+        filetime::set_file_times(
+            partial_path,
+            FileTime::from_last_access_time(metadata),
+            FileTime::from_last_modification_time(metadata),
+        )
+        .with_context(|| "Failed to preserve timestamps")?;
+    }
+    if !options.no_preserve_perms {
+        copy_permissions(metadata, partial_path)?;
+    }
+    Ok(fs::metadata(partial_path)?.len())
+}
+
+/// Path the original of a file that is recompressed in place is moved to
+fn backup_path_of(source_path: &Path, root: &Path, options: &Options) -> Result<PathBuf> {
+    let Some(backup_dir) = &options.backup_dir else {
+        return Ok(unique_path(source_path, "backup"));
+    };
+    let path = mirrored_path(source_path, root, backup_dir);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create backup directory {parent:?}"))?;
+    }
+    // Backups of earlier runs are never overwritten
+    if path.exists() {
+        return Ok(unique_path(&path, "backup"));
+    }
+    Ok(path)
+}
+
+/// Replaces the original with its output, with `keep_files` the original is moved to its backup path
+/// first and moved back if the output can't be moved into place
+fn replace_original(
+    source_path: &Path,
+    partial_path: &Path,
+    output_path: &Path,
+    root: &Path,
+    options: &Options,
+) -> Result<()> {
+    let mut backup_path = None;
+    if options.keep_files {
+        let path = backup_path_of(source_path, root, options)?;
+        move_file(source_path, &path)
+            .with_context(|| format!("Failed to move original to {path:?}"))?;
+        backup_path = Some(path);
+    }
+    // Renaming replaces the original atomically
+    if let Err(err) = commit_output(partial_path, output_path) {
+        if let Some(backup_path) = backup_path {
+            move_file(&backup_path, source_path).unwrap_or_default();
+        }
+        return Err(err);
+    }
+    // On case-sensitive file systems an output that only differs in case is written next to the
+    // original
+    if source_path != output_path && source_path.exists() && !is_same_file(source_path, output_path)
+    {
+        fs::remove_file(source_path).with_context(|| "Failed to remove original file")?;
+    }
+    Ok(())
+}
+
+/// Moves a file, copying it when the destination is on another file system
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+/// Describes why a file can't be replaced or removed, `None` if it can
+fn readonly_reason(path: &Path) -> Option<&'static str> {
+    if is_readonly(path) {
+        Some("the file is read-only")
+    } else if path.parent().is_some_and(is_readonly) {
+        Some("its directory is read-only")
+    } else {
+        None
+    }
+}
+
+fn is_readonly(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.permissions().readonly())
+}
+
+// Directories made writable with --force, their original permissions and amount of users
+static WRITABLE_DIRS: Mutex<Vec<(PathBuf, fs::Permissions, usize)>> = Mutex::new(Vec::new());
+
+/// Makes a read-only directory writable until every guard of the directory is dropped
+struct WritableDir {
+    path: PathBuf,
+}
+
+impl WritableDir {
+    fn new(path: &Path) -> Result<Self> {
+        let mut dirs = WRITABLE_DIRS.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some((_, _, users)) = dirs.iter_mut().find(|(dir, _, _)| dir == path) {
+            *users += 1;
+        } else {
+            let permissions = fs::metadata(path)?.permissions();
+            let mut writable = permissions.clone();
+            // Only the owner gets write access, instead of everyone
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                writable.set_mode(permissions.mode() | 0o200);
+            }
+            #[cfg(not(unix))]
+            #[allow(clippy::permissions_set_readonly_false)]
+            writable.set_readonly(false);
+            fs::set_permissions(path, writable)
+                .with_context(|| format!("Failed to make directory {path:?} writable"))?;
+            dirs.push((path.to_path_buf(), permissions, 1));
+        }
+        Ok(WritableDir {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for WritableDir {
+    fn drop(&mut self) {
+        let mut dirs = WRITABLE_DIRS.lock().unwrap_or_else(|err| err.into_inner());
+        let Some(index) = dirs.iter().position(|(dir, _, _)| *dir == self.path) else {
+            return;
+        };
+        dirs[index].2 -= 1;
+        if dirs[index].2 == 0 {
+            let (path, permissions, _) = dirs.remove(index);
+            if let Err(err) = fs::set_permissions(&path, permissions) {
+                error!("Failed to restore permissions of directory {path:?}: {err}");
+            }
+        }
+    }
+}
+
+/// Creates the temporary file next to the output that the compressed file is written to, the
+/// extension is kept because ImageMagick and FFmpeg pick the format from it. The file is created
+/// exclusively so parallel workers writing to the same directory never share one
+fn partial_path(output_path: &Path) -> Result<PathBuf> {
+    let stem = output_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let ext = output_path
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let mut partial = output_path.with_file_name(format!("{stem}.partial{ext}"));
+    let mut n = 1;
+    loop {
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+        {
+            Ok(_) => return Ok(partial),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                partial = output_path.with_file_name(format!("{stem}.{n}.partial{ext}"));
+                n += 1;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to create output file {partial:?}"))
+            }
+        }
+    }
+}
+
+/// Whether a file is named like a [`partial_path`], these are left behind when a run is killed
+fn is_partial(path: &Path) -> bool {
+    path.file_stem()
+        .is_some_and(|stem| stem.to_string_lossy().ends_with(".partial"))
+}
+
+/// Moves a finished output into place, the data is flushed first so a crash can't leave a truncated
+/// file behind under the output name
+fn commit_output(partial_path: &Path, output_path: &Path) -> Result<()> {
+    fs::File::open(partial_path)
+        .and_then(|file| file.sync_all())
+        .with_context(|| "Failed to flush output file")?;
+    fs::rename(partial_path, output_path)
+        .with_context(|| format!("Failed to move output to {output_path:?}"))
+}
+
+/// Decodes an image once and writes it to each output and its thumbnail, returns for every output
+/// whether it reached the minimum SSIM
+fn compress_image(
+    input_path: &Path,
+    outputs: &[(PathBuf, Option<PathBuf>)],
+    options: &Options,
+) -> Result<Vec<bool>> {
+    let input_str = input_path.to_string_lossy().to_string();
+
+    // Must outlive the wand, the progress monitor reads it
+    let deadline = options.timeout().map(|timeout| Instant::now() + timeout);
+    let mut wand = MagickWand::new();
+    if let Some(deadline) = &deadline {
+        // SAFETY: the deadline is declared before the wand, so it's dropped after it
+        unsafe { magick_ffi::set_deadline(&wand, deadline) };
+    }
+
+    if let Some(quality) = options.image_quality {
+        wand.set_compression_quality(quality as usize)
+            .map_err(|_| magick_error(&wand, "Failed to set compression quality."))?;
+    }
+
+    wand.read_image(&input_str)
+        .map_err(|_| magick_error(&wand, "Failed to read image."))?;
+
+    let frames = magick_ffi::number_images(&wand);
+    if frames > 1
+        && outputs
+            .iter()
+            .any(|(output_path, _)| !is_animated_image_format(output_path))
+    {
+        debug!("Only the first of {frames} frames of {input_path:?} is kept");
+    }
+
+    // Frames of animations can be smaller than the canvas, coalescing gives every frame the full
+    // canvas so they're all resized alike
+    let limits_size = options.max_width.is_some() || options.max_height.is_some();
+    if frames > 1 && limits_size {
+        wand = magick_ffi::coalesce_images(&wand)
+            .ok_or_else(|| magick_error(&wand, "Failed to coalesce frames."))?;
+        if let Some(deadline) = &deadline {
+            // SAFETY: the deadline is declared before the wand, so it's dropped after it
+            unsafe { magick_ffi::set_deadline(&wand, deadline) };
+        }
+    }
+
+    // Downscale images that exceed the maximum dimensions, keeping the aspect ratio
+    let (width, height) = (wand.get_image_width(), wand.get_image_height());
+    let max_width = options.max_width.unwrap_or(width);
+    let max_height = options.max_height.unwrap_or(height);
+    let resize = width > max_width || height > max_height;
+
+    // Every frame is converted, stripped and resized, ImageMagick only changes the current one
+    magick_ffi::reset_iterator(&wand);
+    while magick_ffi::next_image(&wand) {
+        // Converted before stripping, the embedded color profile describes the source colors
+        if options.to_srgb {
+            convert_to_srgb(&wand, options.srgb_profile.as_deref())?;
+        }
+        if options.strip_metadata {
+            wand.profile_image("*", None)
+                .map_err(|_| magick_error(&wand, "Failed to strip metadata."))?;
+        }
+        if resize {
+            wand.fit(max_width.min(width), max_height.min(height));
+        }
+    }
+    magick_ffi::reset_iterator(&wand);
+
+    // Metadata profiles of the first frame that are re-applied before writing the output
+    let mut profiles = Vec::new();
+    if !options.strip_metadata {
+        for name in PRESERVED_PROFILES {
+            if let Some(profile) = magick_ffi::image_profile(&wand, name) {
+                profiles.push((name, profile));
+            }
+        }
+    }
+
+    let Some(((last_path, last_thumbnail), others)) = outputs.split_last() else {
+        return Ok(Vec::new());
+    };
+    let mut quality_met = Vec::new();
+    // The other outputs get a copy, so the format options of one output don't carry over
+    for (output_path, thumbnail_path) in others {
+        quality_met.push(write_image_output(
+            wand.clone(),
+            input_path,
+            output_path,
+            thumbnail_path.as_deref(),
+            frames,
+            &profiles,
+            deadline.as_ref(),
+            options,
+        )?);
+    }
+    quality_met.push(write_image_output(
+        wand,
+        input_path,
+        last_path,
+        last_thumbnail.as_deref(),
+        frames,
+        &profiles,
+        deadline.as_ref(),
+        options,
+    )?);
+    Ok(quality_met)
+}
+
+/// Writes a decoded image to an output, returns `false` if it doesn't reach the minimum SSIM
+#[allow(clippy::too_many_arguments)]
+fn write_image_output(
+    mut wand: MagickWand,
+    input_path: &Path,
+    output_path: &Path,
+    thumbnail_path: Option<&Path>,
+    frames: usize,
+    profiles: &[(&str, Vec<u8>)],
+    deadline: Option<&Instant>,
+    options: &Options,
+) -> Result<bool> {
+    let output_str = output_path.to_string_lossy().to_string();
+    // Copies don't share the progress monitor of the decoded image
+    if let Some(deadline) = deadline {
+        // SAFETY: the wand is dropped when this function returns, the deadline is borrowed longer
+        unsafe { magick_ffi::set_deadline(&wand, deadline) };
+    }
+
+    let is_gif = |path: &Path| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+    };
+    if is_gif(input_path) && is_gif(output_path) {
+        wand = optimize_gif(wand, options.gif_colors)?;
+        if let Some(deadline) = deadline {
+            // SAFETY: the wand is dropped when this function returns, the deadline is borrowed longer
+            unsafe { magick_ffi::set_deadline(&wand, deadline) };
+        }
+    }
+
+    let is_png = output_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    if is_png {
+        if let Some(level) = options.png_level {
+            wand.set_option("png:compression-level", &level.to_string())
+                .map_err(|_| magick_error(&wand, "Failed to set PNG compression level."))?;
+        }
+    }
+    let is_webp = output_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("webp"));
+    if is_webp {
+        if options.webp_lossless {
+            wand.set_option("webp:lossless", "true")
+                .map_err(|_| magick_error(&wand, "Failed to enable lossless WebP."))?;
+        }
+        // Lossy WebP is always 4:2:0, sharp YUV conversion keeps colored edges crisper instead
+        if options
+            .subsampling
+            .is_some_and(|subsampling| subsampling != Subsampling::Quarter)
+        {
+            wand.set_option("webp:use-sharp-yuv", "true")
+                .map_err(|_| magick_error(&wand, "Failed to enable sharp YUV."))?;
+        }
+        if let Some(method) = options.webp_method {
+            wand.set_option("webp:method", &method.to_string())
+                .map_err(|_| magick_error(&wand, "Failed to set WebP method."))?;
+        }
+    }
+    let is_avif = output_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("avif"));
+    if is_avif {
+        // ImageMagick encodes AVIF with the HEIC coder
+        if let Some(speed) = options.avif_speed {
+            wand.set_option("heic:speed", &speed.to_string())
+                .map_err(|_| magick_error(&wand, "Failed to set AVIF speed."))?;
+        }
+    }
+
+    let is_jpeg = output_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"));
+    if is_jpeg {
+        if let Some(subsampling) = options.subsampling {
+            wand.set_option("jpeg:sampling-factor", subsampling.name())
+                .map_err(|_| magick_error(&wand, "Failed to set JPEG subsampling."))?;
+        }
+    }
+
+    for (key, value) in &options.magick_defines {
+        wand.set_option(key, value)
+            .map_err(|_| magick_error(&wand, &format!("Failed to set option '{key}'.")))?;
+    }
+
+    for (name, profile) in profiles {
+        if !magick_ffi::set_image_profile(&wand, name, profile) {
+            return Err(magick_error(
+                &wand,
+                &format!("Failed to set {name} profile."),
+            ));
+        }
+    }
+    let is_mozjpeg = is_jpeg && options.jpeg_backend == JpegBackend::Mozjpeg;
+    // mozjpeg doesn't use the quality of the input, so its default is the starting point
+    let mut quality = match is_mozjpeg {
+        true => Some(options.image_quality.unwrap_or(75)),
+        false => options.image_quality,
+    };
+    loop {
+        if let (true, Some(quality)) = (is_mozjpeg, quality) {
+            write_mozjpeg(&wand, output_path, quality, options.subsampling)?;
+        } else if frames > 1 && is_animated_image_format(output_path) {
+            // Writing all frames to one file keeps the animation
+            wand.write_images(&output_str, true)
+                .map_err(|_| magick_error(&wand, "Failed to write animated image."))?;
+        } else {
+            wand.write_image(&output_str)
+                .map_err(|_| magick_error(&wand, "Failed to write image."))?;
+        }
+
+        let Some(min_ssim) = options.min_ssim else {
+            break;
+        };
+        // Without a quality option the quality of the input is used, or the default of 75
+        let current = quality.unwrap_or(match wand.get_image_compression_quality() {
+            0 => 75,
+            quality => quality as u16,
+        });
+        let ssim = measure_ssim(&wand, output_path)?;
+        debug!("SSIM of {input_path:?} is {ssim:.4} at quality {current}");
+        if ssim >= min_ssim {
+            break;
+        }
+        if current >= 100 {
+            return Ok(false);
+        }
+        let next = (current + 5).min(100);
+        wand.set_compression_quality(next as usize)
+            .map_err(|_| magick_error(&wand, "Failed to set compression quality."))?;
+        quality = Some(next);
+    }
+
+    if let (Some(size), Some(thumbnail_path)) = (options.thumbnail, thumbnail_path) {
+        write_thumbnail(&wand, thumbnail_path, size)?;
+    }
+
+    if is_png && options.png_backend == PngBackend::Oxipng {
+        // The oxipng presets range from 0 to 6
+        let preset = options.png_level.unwrap_or(2).min(6);
+        oxipng::optimize(
+            &oxipng::InFile::Path(output_path.to_path_buf()),
+            &oxipng::OutFile::from_path(output_path.to_path_buf()),
+            &oxipng::Options {
+                timeout: options.timeout(),
+                ..oxipng::Options::from_preset(preset)
+            },
+        )
+        .with_context(|| "Failed to optimize PNG with oxipng.")?;
+    }
+    Ok(true)
+}
+
+/// Structural similarity between the image and the written output, 1 if they are identical
+fn measure_ssim(wand: &MagickWand, output_path: &Path) -> Result<f64> {
+    let output = MagickWand::new();
+    output
+        .read_image(&output_path.to_string_lossy())
+        .map_err(|_| magick_error(&output, "Failed to read output image."))?;
+    // The dissimilarity is (1 - SSIM) / 2
+    let (dssim, _) = wand.compare_images(
+        &output,
+        bindings::MetricType_StructuralDissimilarityErrorMetric,
+    );
+    Ok(1.0 - 2.0 * dssim)
+}
+
+/// Checks if ImageMagick was built with a delegate library, e.g. heic or raw
+pub fn magick_has_delegate(name: &str) -> bool {
+    magick_ffi::delegates()
+        .iter()
+        .any(|delegate| delegate == name)
+}
+
+/// Checks if ImageMagick can read raw camera images, with libraw or the dcraw or ufraw-batch program
+pub fn magick_supports_raw() -> bool {
+    let on_path = |program: &str| {
+        env::var_os("PATH")
+            .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+    };
+    magick_has_delegate("raw") || on_path("dcraw") || on_path("ufraw-batch")
+}
+
+/// Error including the exception of the wand, magick_rust only returns generic error messages
+fn magick_error(wand: &MagickWand, message: &str) -> anyhow::Error {
+    match wand.get_exception() {
+        Ok((exception, _)) if !exception.is_empty() => anyhow!("{message} {exception}"),
+        _ => anyhow!("{message}"),
+    }
+}
+
+/// Encodes the image as JPEG with mozjpeg, keeping the EXIF, XMP and ICC profiles
+fn write_mozjpeg(
+    wand: &MagickWand,
+    output_path: &Path,
+    quality: u16,
+    subsampling: Option<Subsampling>,
+) -> Result<()> {
+    let (width, height) = (wand.get_image_width(), wand.get_image_height());
+    let pixels = wand
+        .export_image_pixels(0, 0, width, height, "RGB")
+        .ok_or_else(|| magick_error(wand, "Failed to export image pixels."))?;
+    let exif = magick_ffi::image_profile(wand, "exif");
+    let xmp = magick_ffi::image_profile(wand, "xmp");
+    let icc = magick_ffi::image_profile(wand, "icc");
+
+    // mozjpeg reports errors by panicking
+    let jpeg = panic::catch_unwind(|| -> io::Result<Vec<u8>> {
+        let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        compress.set_size(width, height);
+        compress.set_quality(quality as f32);
+        if let Some(subsampling) = subsampling {
+            let size = subsampling.pixel_size();
+            compress.set_chroma_sampling_pixel_sizes(size, size);
+        }
+        let mut compress = compress.start_compress(Vec::new())?;
+        if let Some(exif) = &exif {
+            compress.write_marker(mozjpeg::Marker::APP(1), exif);
+        }
+        if let Some(xmp) = &xmp {
+            let mut marker = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+            marker.extend_from_slice(xmp);
+            compress.write_marker(mozjpeg::Marker::APP(1), &marker);
+        }
+        if let Some(icc) = &icc {
+            compress.write_icc_profile(icc);
+        }
+        compress.write_scanlines(&pixels)?;
+        compress.finish()
+    })
+    .map_err(|_| anyhow!("Failed to encode JPEG with mozjpeg."))?
+    .with_context(|| "Failed to encode JPEG with mozjpeg.")?;
+    fs::write(output_path, jpeg).with_context(|| "Failed to write image.")
+}
+
+/// Video formats animated images can be converted to
+const ANIMATION_FORMATS: &[&str] = &["mp4", "mkv", "mov", "webm"];
+
+/// Optimizes a GIF by reducing the colors and only storing the changed pixels of each frame
+fn optimize_gif(wand: MagickWand, colors: Option<usize>) -> Result<MagickWand> {
+    let coalesced = magick_ffi::coalesce_images(&wand)
+        .ok_or_else(|| magick_error(&wand, "Failed to coalesce GIF frames."))?;
+    // All frames get the same palette, dithering is left out because the noise compresses poorly
+    if let Some(colors) = colors {
+        coalesced
+            .quantize_images(
+                colors,
+                bindings::ColorspaceType_sRGBColorspace,
+                0,
+                bindings::DitherMethod_NoDitherMethod,
+                bindings::MagickBooleanType_MagickFalse,
+            )
+            .map_err(|_| magick_error(&coalesced, "Failed to reduce GIF colors."))?;
+    }
+    // Frames that are the same as the previous one shrink to a single pixel
+    let optimized = magick_ffi::optimize_image_layers(&coalesced)
+        .ok_or_else(|| magick_error(&coalesced, "Failed to optimize GIF frames."))?;
+    if !magick_ffi::optimize_image_transparency(&optimized) {
+        return Err(magick_error(
+            &optimized,
+            "Failed to optimize GIF transparency.",
+        ));
+    }
+    Ok(optimized)
+}
+
+/// Image formats that keep all frames of animated images, such as animated GIF and WebP
+const ANIMATED_IMAGE_FORMATS: &[&str] = &["gif", "webp"];
+
+fn is_animated_image_format(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        ANIMATED_IMAGE_FORMATS
+            .iter()
+            .any(|format| ext.eq_ignore_ascii_case(format))
+    })
+}
+
+/// Amount of frames in an image
+fn image_frames(path: &Path) -> Result<usize> {
+    let wand = MagickWand::new();
+    wand.ping_image(&path.to_string_lossy())
+        .map_err(|_| magick_error(&wand, "Failed to read image."))?;
+    Ok(magick_ffi::number_images(&wand))
+}
+
+/// Path of the `<name>.thumb.<ext>` thumbnail next to the output, `None` for thumbnails themselves
+fn thumbnail_path(output_path: &Path) -> Option<PathBuf> {
+    let stem = output_path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    // Don't create thumbnails of thumbnails
+    if stem.ends_with(".thumb") {
+        return None;
+    }
+    let mut file_name = format!("{stem}.thumb");
+    if let Some(ext) = output_path.extension() {
+        file_name = format!("{file_name}.{}", ext.to_string_lossy());
+    }
+    Some(output_path.with_file_name(file_name))
+}
+
+/// Writes a thumbnail that fits within `size` pixels
+fn write_thumbnail(wand: &MagickWand, thumbnail_path: &Path, size: usize) -> Result<()> {
+    let (width, height) = (wand.get_image_width(), wand.get_image_height());
+    let ratio = (size as f64 / width.max(1) as f64)
+        .min(size as f64 / height.max(1) as f64)
+        .min(1.0);
+    let columns = ((width as f64 * ratio).round() as usize).max(1);
+    let rows = ((height as f64 * ratio).round() as usize).max(1);
+
+    let thumbnail = wand.clone();
+    if !magick_ffi::thumbnail(&thumbnail, columns, rows) {
+        return Err(magick_error(&thumbnail, "Failed to create thumbnail."));
+    }
+    thumbnail
+        .write_image(&thumbnail_path.to_string_lossy())
+        .map_err(|_| {
+            magick_error(
+                &thumbnail,
+                &format!("Failed to write thumbnail {thumbnail_path:?}."),
+            )
+        })
+}
+
+/// Common locations of an sRGB ICC profile on Linux
+pub const SRGB_PROFILES: [&str; 3] = [
+    "/usr/share/color/icc/sRGB.icc",
+    "/usr/share/color/icc/colord/sRGB.icc",
+    "/usr/share/color/icc/ghostscript/srgb.icc",
+];
+
+/// Converts an image to sRGB, with the ICC profile if a color profile is embedded
+fn convert_to_srgb(wand: &MagickWand, srgb_profile: Option<&Path>) -> Result<()> {
+    if let Some(srgb_profile) = srgb_profile {
+        if magick_ffi::image_profile(wand, "icc").is_some() {
+            let profile = fs::read(srgb_profile)
+                .with_context(|| format!("Failed to read sRGB profile {srgb_profile:?}"))?;
+            return wand
+                .profile_image("icc", Some(profile.as_slice()))
+                .map_err(|_| magick_error(wand, "Failed to convert color profile to sRGB."));
+        }
+    }
+    if wand.get_image_colorspace() != bindings::ColorspaceType_sRGBColorspace {
+        wand.transform_image_colorspace(bindings::ColorspaceType_sRGBColorspace)
+            .map_err(|_| magick_error(wand, "Failed to convert colorspace to sRGB."))?;
+    }
+    Ok(())
+}
+
+/// Image profiles containing EXIF and XMP metadata such as orientation, GPS and capture dates
+const PRESERVED_PROFILES: [&str; 2] = ["exif", "xmp"];
+
+/// Encoder settings of a video codec
+struct VideoCodec {
+    /// FFMPEG encoder name
+    encoder: String,
+    default_crf: u8,
+    max_crf: u8,
+    /// Arguments added after the CRF
+    extra_args: &'static [&'static str],
+}
+
+impl VideoCodec {
+    /// Looks up the settings of an encoder, defaults to H.265
+    fn from_name(name: Option<&str>) -> Self {
+        let encoder = name.unwrap_or("libx265");
+        let (default_crf, max_crf, extra_args): (u8, u8, &'static [&'static str]) = match encoder {
+            "libx265" => (28, 51, &[]),
+            "libx264" => (23, 51, &[]),
+            // See: https://trac.ffmpeg.org/wiki/Encode/AV1
+            "libaom-av1" => (30, 63, &["-b:v", "0"]),
+            "libsvtav1" => (35, 63, &[]),
+            // See: https://trac.ffmpeg.org/wiki/Encode/VP9
+            "libvpx-vp9" => (31, 63, &["-b:v", "0"]),
+            _ => (28, 51, &[]),
+        };
+        VideoCodec {
+            encoder: encoder.to_string(),
+            default_crf,
+            max_crf,
+            extra_args,
+        }
+    }
+
+    /// Encoder arguments, a target bitrate in kbps takes precedence over the CRF
+    fn args(&self, crf: Option<u8>, target_bitrate: Option<u32>) -> Vec<String> {
+        let mut args = vec!["-vcodec".to_string(), self.encoder.clone()];
+        match target_bitrate {
+            Some(bitrate) => args.extend(["-b:v".to_string(), format!("{bitrate}k")]),
+            None => {
+                args.extend([
+                    "-crf".to_string(),
+                    crf.unwrap_or(self.default_crf).to_string(),
+                ]);
+                // The extra arguments only apply to CRF encoding
+                args.extend(self.extra_args.iter().map(|arg| arg.to_string()));
+            }
+        }
+        args
+    }
+
+    /// WebM only supports the VP8, VP9 and AV1 codecs
+    fn supports_webm(&self) -> bool {
+        matches!(
+            self.encoder.as_str(),
+            "libvpx" | "libvpx-vp9" | "libaom-av1" | "libsvtav1"
+        )
+    }
+
+    /// Arguments for a pass of a two-pass encode, the statistics are written to `passlog`
+    fn pass_args(&self, pass: u8, passlog: &Path) -> Vec<String> {
+        let passlog = passlog.to_string_lossy();
+        if self.encoder == "libx265" {
+            // x265 doesn't support the generic pass options
+            vec![
+                "-x265-params".to_string(),
+                format!("pass={pass}:stats={passlog}"),
+            ]
+        } else {
+            vec![
+                "-pass".to_string(),
+                pass.to_string(),
+                "-passlogfile".to_string(),
+                passlog.to_string(),
+            ]
+        }
+    }
+}
+
+impl HwAccel {
+    /// Hardware encoder of the same codec family as the software encoder
+    fn encoder(self, software_encoder: &str) -> String {
+        let family = match software_encoder {
+            "libx264" => "h264",
+            "libaom-av1" | "libsvtav1" => "av1",
+            "libvpx-vp9" => "vp9",
+            _ => "hevc",
+        };
+        let suffix = match self {
+            HwAccel::Vaapi => "vaapi",
+            HwAccel::Nvenc => "nvenc",
+            HwAccel::Qsv => "qsv",
+        };
+        format!("{family}_{suffix}")
+    }
+
+    /// Encoder arguments, hardware encoders don't support a CRF but have similar constant quality modes
+    fn args(
+        self,
+        codec: &VideoCodec,
+        crf: Option<u8>,
+        target_bitrate: Option<u32>,
+        vaapi_device: &str,
+    ) -> Vec<String> {
+        let mut args = Vec::new();
+        if self == HwAccel::Vaapi {
+            args.extend(["-vaapi_device".to_string(), vaapi_device.to_string()]);
+        }
+        args.extend(["-vcodec".to_string(), self.encoder(&codec.encoder)]);
+        let quality = crf.unwrap_or(codec.default_crf).to_string();
+        match (target_bitrate, self) {
+            (Some(bitrate), _) => args.extend(["-b:v".to_string(), format!("{bitrate}k")]),
+            (None, HwAccel::Vaapi) => args.extend(["-rc_mode", "CQP", "-qp"].map(String::from)),
+            (None, HwAccel::Nvenc) => {
+                args.extend(["-rc", "vbr", "-b:v", "0", "-cq"].map(String::from))
+            }
+            (None, HwAccel::Qsv) => args.push("-global_quality".to_string()),
+        }
+        if target_bitrate.is_none() {
+            args.push(quality);
+        }
+        args
+    }
+
+    /// Filter that uploads the frames to the GPU, VAAPI encoders only accept frames in GPU memory
+    fn upload_filter(self) -> Option<&'static str> {
+        match self {
+            HwAccel::Vaapi => Some("format=nv12,hwupload"),
+            HwAccel::Nvenc | HwAccel::Qsv => None,
+        }
+    }
+}
+
+/// Checks that FFMPEG is installed before any audio or video files are compressed
+pub fn check_ffmpeg() -> Result<()> {
+    let status = Command::new("ffmpeg")
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(anyhow!(
+            "FFMPEG is required to compress audio and video files, install it from https://ffmpeg.org/download.html and make sure it is in your PATH."
+        )),
+    }
+}
+
+/// Video formats encoded with the video codec settings
+const VIDEO_FORMATS: &[&str] = &["mp4", "mkv", "mov", "avi", "flv", "webm"];
+
+/// Formats encoded with the default FFMPEG settings
+const FFMPEG_DEFAULT_FORMATS: &[&str] = &["wav"];
+
+/// FFMPEG arguments for an output format, or `None` if the format is unknown
+fn ffmpeg_args(
+    output_ext: &str,
+    video_args: &[String],
+    audio_bitrate: Option<&str>,
+) -> Option<Vec<String>> {
+    // Lossy audio codec with its default bitrate
+    let lossy_audio = |codec: &str, bitrate: &str| {
+        ["-c:a", codec, "-b:a", audio_bitrate.unwrap_or(bitrate)]
+            .map(String::from)
+            .to_vec()
+    };
+    // TODO: Find better settings
+    let args = match output_ext {
+        // Audio Lossy, see: https://trac.ffmpeg.org/wiki/Encode/MP3
+        "mp3" => match audio_bitrate {
+            Some(bitrate) => vec!["-b:a".to_string(), bitrate.to_string()],
+            None => vec!["-qscale:a".to_string(), "2".to_string()],
+        },
+        "opus" => lossy_audio("libopus", "96k"),
+        "ogg" => lossy_audio("libvorbis", "128k"),
+        "m4a" => lossy_audio("aac", "128k"),
+        // Audio Loseless, max FLAC compression
+        "flac" => vec!["-compression_level".to_string(), "12".to_string()],
+        // Video Lossy, WebM only supports Opus and Vorbis audio
+        "webm" => [video_args.to_vec(), lossy_audio("libopus", "96k")].concat(),
+        ext if VIDEO_FORMATS.contains(&ext) => video_args.to_vec(),
+        ext if FFMPEG_DEFAULT_FORMATS.contains(&ext) => Vec::new(),
+        _ => return None,
+    };
+    Some(args)
+}
+
+/// Checks that all audio and video extensions have FFMPEG settings
+pub fn check_extensions(extensions: &HashMap<&str, MediaType>) -> Result<()> {
+    for (ext, media_type) in extensions {
+        if *media_type != MediaType::Image && ffmpeg_args(ext, &[], None).is_none() {
+            return Err(anyhow!("Missing FFMPEG settings for extension '{ext}'."));
+        }
+    }
+    Ok(())
+}
+
+/// Raw camera formats, read through the raw delegate of ImageMagick and converted to JPEG by default
+pub const RAW_FORMATS: &[&str] = &["cr2", "nef"];
+
+/// Formats that store images or audio without loss
+const LOSSLESS_FORMATS: &[&str] = &["png", "bmp", "gif", "tiff", "cr2", "nef", "flac", "wav"];
+
+/// Formats that always discard detail when encoding
+const LOSSY_FORMATS: &[&str] = &[
+    "jpg", "avif", "heic", "heif", "webp", "mp3", "ogg", "opus", "m4a",
+];
+
+/// Warns once per conversion about files that are converted to a lossy format of a different kind
+pub fn check_lossy_conversions(index: &[MediaIndex], options: &Options) {
+    if options.allow_lossy {
+        return;
+    }
+    let mut conversions: BTreeMap<(String, String), usize> = BTreeMap::new();
+    for file in index {
+        // Copied streams aren't encoded again
+        if options.copy_streams && file.media_type != MediaType::Image {
+            continue;
+        }
+        for (_, output_ext) in output_targets(file, options) {
+            let (from, to) = (
+                canonical_format(&file.format),
+                canonical_format(&output_ext),
+            );
+            let lossy =
+                LOSSY_FORMATS.contains(&to.as_str()) && !(to == "webp" && options.webp_lossless);
+            if from != to && lossy {
+                *conversions.entry((from, to)).or_default() += 1;
+            }
+        }
+    }
+    for ((from, to), count) in conversions {
+        let loss = match LOSSLESS_FORMATS.contains(&from.as_str()) {
+            true => "discard detail of lossless files",
+            false => "add another generation of compression loss",
+        };
+        let conversions = if count == 1 {
+            "conversion"
+        } else {
+            "conversions"
+        };
+        warn!(
+            "{count} {}→{} {conversions} will {loss}, use --allow-lossy to hide this warning.",
+            from.to_uppercase(),
+            to.to_uppercase()
+        );
+    }
+}
+
+/// Checks the output formats given as options, so typos are caught before compression starts
+pub fn check_output_formats(
+    options: &Options,
+    extensions: &HashMap<&str, MediaType>,
+) -> Result<()> {
+    let supports = |media_type: Option<&MediaType>, ext: &str| {
+        let image =
+            extensions.get(ext) == Some(&MediaType::Image) || ANIMATION_FORMATS.contains(&ext);
+        let ffmpeg = ffmpeg_args(ext, &[], None).is_some();
+        match media_type {
+            Some(MediaType::Image) => image,
+            Some(MediaType::Audio | MediaType::Video) => ffmpeg,
+            None => image || ffmpeg,
+        }
+    };
+    let image_flags = options
+        .image_ext
+        .iter()
+        .flatten()
+        .map(|ext| (MediaType::Image, ext));
+    let flags: Vec<(MediaType, &String)> = image_flags
+        .chain(
+            [
+                (MediaType::Audio, &options.audio_ext),
+                (MediaType::Video, &options.video_ext),
+            ]
+            .into_iter()
+            .filter_map(|(media_type, ext)| Some((media_type, ext.as_ref()?.as_ref()?))),
+        )
+        .collect();
+    let mut targets: Vec<(Option<&MediaType>, String)> = flags
+        .iter()
+        .map(|(media_type, ext)| (Some(media_type), *ext))
+        .map(|(media_type, ext)| (media_type, ext.trim_start_matches('.').to_lowercase()))
+        .filter(|(_, ext)| !ext.is_empty())
+        .collect();
+    for (from, to) in options.format_map.iter() {
+        targets.push((extensions.get(from.as_str()), to.clone()));
+    }
+
+    for (media_type, ext) in targets {
+        if supports(media_type, &ext) {
+            continue;
+        }
+        let message = match media_type {
+            Some(media_type) => format!("Unknown output format '{ext}' for {media_type:?} files."),
+            None => format!("Unknown output format '{ext}'."),
+        };
+        if options.strict {
+            return Err(anyhow!(message));
+        }
+        warn!("{message}");
+    }
+    Ok(())
+}
+
+fn compress_ffmpeg(
+    input_path: &Path,
+    output_path: &Path,
+    output_ext: &str,
+    media_type: &MediaType,
+    options: &Options,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let input_str = input_path.to_string_lossy().to_string();
+    let output_str = output_path.to_string_lossy().to_string();
+
+    let timeout = options.timeout();
+    let mut codec = VideoCodec::from_name(options.video_codec.as_deref());
+    if output_ext == "webm" && !codec.supports_webm() {
+        codec = VideoCodec::from_name(Some("libvpx-vp9"));
+    }
+    let video_args = match options.hwaccel {
+        Some(hwaccel) => hwaccel.args(
+            &codec,
+            options.video_crf,
+            options.video_target_bitrate,
+            options
+                .vaapi_device
+                .as_deref()
+                .unwrap_or("/dev/dri/renderD128"),
+        ),
+        None => codec.args(options.video_crf, options.video_target_bitrate),
+    };
+
+    // Images are only compressed with FFMPEG when they are animated
+    let animation = *media_type == MediaType::Image;
+
+    let mut filters = Vec::new();
+    // Only downscale, -2 keeps the width divisible by 2
+    if let Some(height) = options.video_max_height {
+        filters.push(format!("scale=-2:'min(ih,{height})'"));
+    }
+    if animation {
+        // Most video encoders require even dimensions
+        filters.push("scale=trunc(iw/2)*2:trunc(ih/2)*2".to_string());
+    }
+    // Uploading must be the last filter, the other filters run on the CPU
+    if let Some(upload) = options.hwaccel.and_then(HwAccel::upload_filter) {
+        filters.push(upload.to_string());
+    }
+    let filter = filters.join(",");
+
+    if options.copy_streams && !animation {
+        let mut args = vec!["-c".to_string(), "copy".to_string()];
+        args.extend(stream_args(options));
+        args.extend(options.ffmpeg_args.iter().cloned());
+        return run_ffmpeg(
+            &input_str,
+            &args,
+            &output_str,
+            timeout,
+            options.retries,
+            cancel,
+        );
+    }
+
+    let mut args =
+        ffmpeg_args(output_ext, &video_args, options.audio_bitrate.as_deref()).unwrap_or_default();
+    if !animation {
+        args.extend(stream_args(options));
+    }
+    if *media_type == MediaType::Video || animation {
+        if !filter.is_empty() {
+            args.extend(["-vf".to_string(), filter]);
+        }
+        if let Some(fps) = options.video_fps {
+            args.extend(["-r".to_string(), fps.to_string()]);
+        }
+    }
+    if *media_type == MediaType::Audio && options.audio_normalize {
+        // -24 LUFS is the default of the loudnorm filter
+        let lufs = options.audio_lufs.unwrap_or(-24.0);
+        args.extend(["-af".to_string(), format!("loudnorm=I={lufs}")]);
+    }
+    // With uploaded frames the upload filter already sets the pixel format
+    if animation && options.hwaccel.and_then(HwAccel::upload_filter).is_none() {
+        // GIFs use a palette, which is not supported by most players
+        args.extend(["-pix_fmt".to_string(), "yuv420p".to_string()]);
+    }
+    args.extend(options.ffmpeg_args.iter().cloned());
+
+    // Hardware encoders hit the bitrate in a single pass
+    let two_pass = options.video_target_bitrate.is_some()
+        && options.hwaccel.is_none()
+        && VIDEO_FORMATS.contains(&output_ext);
+    if !two_pass {
+        return run_ffmpeg(
+            &input_str,
+            &args,
+            &output_str,
+            timeout,
+            options.retries,
+            cancel,
+        );
+    }
+    // The first pass only analyzes the video, the second pass uses the statistics to hit the bitrate
+    let passlog = unique_path(output_path, "passlog");
+    let result = (|| {
+        let mut first_pass = args.clone();
+        first_pass.extend(codec.pass_args(1, &passlog));
+        first_pass.extend(["-an", "-f", "null"].map(String::from));
+        run_ffmpeg(
+            &input_str,
+            &first_pass,
+            "-",
+            timeout,
+            options.retries,
+            cancel,
+        )?;
+        args.extend(codec.pass_args(2, &passlog));
+        run_ffmpeg(
+            &input_str,
+            &args,
+            &output_str,
+            timeout,
+            options.retries,
+            cancel,
+        )
+    })();
+    remove_passlogs(&passlog);
+    result
+}
+
+/// Output size of a video extrapolated from a compressed sample
+#[derive(Debug)]
+pub struct Estimate {
+    pub path: PathBuf,
+    pub original_size: u64,
+    pub estimated_size: u64,
+}
+
+/// Estimates the output size of every video by compressing its first `seconds`, other media types
+/// are left out
+pub fn estimate(
+    index: &[MediaIndex],
+    options: &Options,
+    seconds: u32,
+    cancel: &Cancellation,
+) -> Result<Vec<Estimate>> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.threads())
+        .build()?;
+    let videos: Vec<(usize, &MediaIndex)> = index
+        .iter()
+        .filter(|file| file.media_type == MediaType::Video)
+        .enumerate()
+        .collect();
+    let estimates = pool.install(|| {
+        videos
+            .into_par_iter()
+            .filter_map(|(n, file)| {
+                if cancel.stop.load(Ordering::SeqCst) {
+                    return None;
+                }
+                let options = file.options.as_deref().unwrap_or(options);
+                match estimate_file(file, n, seconds, options, cancel) {
+                    Ok(estimate) => estimate,
+                    Err(err) => {
+                        warn!("Failed to estimate {:?}: {err:#}", file.path);
+                        None
+                    }
+                }
+            })
+            .collect()
+    });
+    Ok(estimates)
+}
+
+fn estimate_file(
+    file: &MediaIndex,
+    n: usize,
+    seconds: u32,
+    options: &Options,
+    cancel: &Cancellation,
+) -> Result<Option<Estimate>> {
+    let Some((_, output_ext)) = output_target(file, options) else {
+        return Ok(None);
+    };
+    let duration = media_duration(&file.path)?;
+    let sample_path = env::temp_dir().join(format!(
+        "comva-estimate-{}-{n}.{output_ext}",
+        std::process::id()
+    ));
+    // Limits the duration of the output, the encoding settings are the same as a full run
+    let mut sample_options = options.clone();
+    sample_options
+        .ffmpeg_args
+        .extend(["-t".to_string(), seconds.to_string()]);
+    let result = compress_ffmpeg(
+        &file.path,
+        &sample_path,
+        &output_ext,
+        &file.media_type,
+        &sample_options,
+        cancel,
+    );
+    let sample_size = fs::metadata(&sample_path).map(|metadata| metadata.len());
+    fs::remove_file(&sample_path).unwrap_or_default();
+    result?;
+    let sample_size = sample_size.with_context(|| "Failed to read sample")?;
+
+    let sampled = duration.min(seconds as f64);
+    if sampled <= 0.0 {
+        return Err(anyhow!("Video has no duration."));
+    }
+    let estimated_size = (sample_size as f64 * duration / sampled) as u64;
+    debug!(
+        "Sample of {:?} is {} for {sampled:.1}s of {duration:.1}s",
+        file.path,
+        format_size(sample_size)
+    );
+    Ok(Some(Estimate {
+        path: file.path.clone(),
+        original_size: fs::metadata(&file.path)?.len(),
+        estimated_size,
+    }))
+}
+
+/// Duration of an audio or video file in seconds, read with FFprobe
+fn media_duration(path: &Path) -> Result<f64> {
+    let mut command = Command::new("ffprobe");
+    command
+        .args(["-v", "error", "-show_entries", "format=duration"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .stdin(Stdio::null());
+    let output = run_with_timeout(&mut command, None, None)?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "FFprobe failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let duration = String::from_utf8_lossy(&output.stdout);
+    duration
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid duration '{}'", duration.trim()))
+}
+
+/// Gives the output the permissions and, where permitted, the owner of the original
+fn copy_permissions(metadata: &fs::Metadata, output_path: &Path) -> Result<()> {
+    // Changing the owner can clear the setuid and setgid bits, so it's done first
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Err(err) =
+            std::os::unix::fs::chown(output_path, Some(metadata.uid()), Some(metadata.gid()))
+        {
+            // Only privileged users can give files away
+            debug!("Failed to preserve owner of {output_path:?}: {err}");
+        }
+    }
+    fs::set_permissions(output_path, metadata.permissions())
+        .with_context(|| "Failed to preserve permissions")
+}
+
+/// Stream selection arguments, by default FFMPEG picks one stream of each type
+fn stream_args(options: &Options) -> Vec<String> {
+    let mut args = Vec::new();
+    if let Some(track) = options.audio_track {
+        // Mapping any stream disables the default selection, the ? ignores missing streams
+        args.extend(["-map", "0:v?"].map(String::from));
+        args.extend(["-map".to_string(), format!("0:a:{track}")]);
+        if !options.drop_subtitles {
+            args.extend(["-map", "0:s?"].map(String::from));
+        }
+    }
+    if options.drop_subtitles {
+        args.push("-sn".to_string());
+    }
+    args
+}
+
+/// Checks that the output can be decoded
+fn verify_output(
+    output_path: &Path,
+    decoded_by_ffmpeg: bool,
+    timeout: Option<Duration>,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let output_str = output_path.to_string_lossy().to_string();
+    if decoded_by_ffmpeg {
+        // Decode the whole file and exit on the first error
+        let args = ["-v", "error", "-xerror", "-f", "null"].map(String::from);
+        return run_ffmpeg(&output_str, &args, "-", timeout, 0, cancel);
+    }
+    let wand = MagickWand::new();
+    wand.read_image(&output_str)
+        .map_err(|_| magick_error(&wand, "Failed to read output image."))?;
+    if wand.get_image_width() == 0 || wand.get_image_height() == 0 {
+        return Err(anyhow!("Output image has no dimensions."));
+    }
+    Ok(())
+}
+
+fn run_ffmpeg(
+    input: &str,
+    args: &[String],
+    output: &str,
+    timeout: Option<Duration>,
+    retries: u32,
+    cancel: &Cancellation,
+) -> Result<()> {
+    let mut command = Command::new("ffmpeg");
+    command
+        .arg("-i")
+        .arg(input)
+        .args(args)
+        .arg("-y") // Overwrite
+        .arg(output)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Use a separate process group so Ctrl-C doesn't interrupt running jobs
+    #[cfg(unix)]
+    command.process_group(0);
+    let command_line = format_command(&command);
+    let mut attempt = 0;
+    loop {
+        debug!("Running {command_line}");
+        let output = run_with_timeout(&mut command, timeout, Some(&cancel.abort))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        // Failures of flaky storage often succeed when tried again a bit later
+        if attempt < retries && !cancel.stop.load(Ordering::SeqCst) {
+            attempt += 1;
+            warn!("FFMPEG failed for {input:?}, retrying ({attempt}/{retries})..");
+            thread::sleep(Duration::from_secs(attempt as u64));
+            continue;
+        }
+        let stdout_str = String::from_utf8_lossy(&output.stdout);
+        let stderr_str = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!(format!(
+            "Failed FFMPEG execution!\nCommand: {command_line}\nStdErr: {stderr_str}\nStdOut: {stdout_str}"
+        )));
+    }
+}
+
+/// Runs a command and collects its output, the command is killed if it runs longer than the timeout
+/// or once `abort` is set
+fn run_with_timeout(
+    command: &mut Command,
+    timeout: Option<Duration>,
+    abort: Option<&AtomicBool>,
+) -> Result<Output> {
+    if timeout.is_none() && abort.is_none() {
+        return command.output().with_context(|| "Failed to run command");
+    }
+    let mut child = command.spawn().with_context(|| "Failed to run command")?;
+    // Read the output on separate threads so the pipes don't fill up
+    let read_pipe = |pipe: Option<Box<dyn io::Read + Send>>| {
+        thread::spawn(move || {
+            let mut data = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut data).unwrap_or_default();
+            }
+            data
+        })
+    };
+    let stdout = read_pipe(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = read_pipe(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() > timeout) {
+            child.kill().unwrap_or_default();
+            child.wait()?;
+            return Err(anyhow!("Timed out after {timeout:?}"));
+        }
+        if abort.is_some_and(|abort| abort.load(Ordering::SeqCst)) {
+            child.kill().unwrap_or_default();
+            child.wait()?;
+            return Err(anyhow!("Aborted"));
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Removes the statistics files of a two-pass encode, encoders add their own suffixes to the name
+fn remove_passlogs(passlog: &Path) {
+    let (Some(directory), Some(prefix)) = (passlog.parent(), passlog.file_name()) else {
+        return;
+    };
+    let prefix = prefix.to_string_lossy();
+    let Ok(entries) = fs::read_dir(directory) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .starts_with(prefix.as_ref())
+        {
+            fs::remove_file(entry.path()).unwrap_or_default();
+        }
+    }
+}
+
+/// Formats a command as a shell command line, quoting arguments where needed
+fn format_command(command: &Command) -> String {
+    let program = command.get_program().to_string_lossy().to_string();
+    let args = command.get_args().map(|arg| {
+        let arg = arg.to_string_lossy();
+        if !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+".contains(c))
+        {
+            arg.to_string()
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    });
+    std::iter::once(program)
+        .chain(args)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory for a test, removing the files of an earlier run
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("comva-test-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or_default();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes an original and its compressed output, returns their paths
+    fn write_original(dir: &Path) -> (PathBuf, PathBuf) {
+        let source_path = dir.join("photo.jpg");
+        fs::write(&source_path, "original").unwrap();
+        let partial_path = partial_path(&source_path).unwrap();
+        fs::write(&partial_path, "compressed").unwrap();
+        (source_path, partial_path)
+    }
+
+    #[test]
+    fn partial_paths_are_never_shared() {
+        let dir = test_dir("partial");
+        let output_path = dir.join("song.mp3");
+        let first = partial_path(&output_path).unwrap();
+        let second = partial_path(&output_path).unwrap();
+        assert_eq!(first, dir.join("song.partial.mp3"));
+        assert_eq!(second, dir.join("song.1.partial.mp3"));
+        assert!(first.exists() && second.exists());
+    }
+
+    /// Indexes audio files that are compressed to mp3
+    fn plan_audio(dir: &Path, names: &[&str], policy: OverwritePolicy) -> Vec<PlannedOutput> {
+        let index: Vec<MediaIndex> = names
+            .iter()
+            .map(|name| MediaIndex {
+                path: dir.join(name),
+                root: dir.to_path_buf(),
+                media_type: MediaType::Audio,
+                format: name.rsplit('.').next().unwrap().to_string(),
+                flat_stem: None,
+                options: None,
+            })
+            .collect();
+        let options = Options {
+            audio_ext: Some(Some("mp3".to_string())),
+            overwrite_policy: policy,
+            ..Default::default()
+        };
+        let mut planned = plan_outputs(&index, &options);
+        index
+            .iter()
+            .flat_map(|file| planned.remove(&file.path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn plan_outputs_renames_duplicates() {
+        let dir = test_dir("plan-rename");
+        fs::write(dir.join("song.mp3"), "existing").unwrap();
+        let outputs = plan_audio(&dir, &["song.wav", "song.flac"], OverwritePolicy::Rename);
+        assert_eq!(outputs[0].path, dir.join("song_1.mp3"));
+        assert_eq!(outputs[1].path, dir.join("song_2.mp3"));
+        assert!(outputs.iter().all(|output| output.conflict.is_none()));
+    }
+
+    #[test]
+    fn plan_outputs_skips_duplicates() {
+        let dir = test_dir("plan-skip");
+        let outputs = plan_audio(&dir, &["song.wav", "song.flac"], OverwritePolicy::Skip);
+        assert_eq!(outputs[0].path, dir.join("song.mp3"));
+        assert_eq!(outputs[0].conflict, None);
+        assert_eq!(outputs[1].conflict, Some(dir.join("song.wav")));
+    }
+
+    #[test]
+    fn plan_outputs_only_overwrites_existing_files() {
+        let dir = test_dir("plan-overwrite");
+        fs::write(dir.join("song.mp3"), "existing").unwrap();
+        let outputs = plan_audio(&dir, &["song.wav", "song.flac"], OverwritePolicy::Overwrite);
+        assert_eq!(outputs[0].path, dir.join("song.mp3"));
+        assert_eq!(outputs[0].conflict, None);
+        assert_eq!(outputs[1].conflict, Some(dir.join("song.wav")));
+    }
+
+    #[test]
+    fn artifacts_of_earlier_runs() {
+        for name in [
+            "photo.partial.jpg",
+            "photo.1.partial.jpg",
+            "photo.thumb.jpg",
+            "photo.jpg.backup",
+            "video.mp4.passlog-0.log",
+        ] {
+            assert!(is_artifact(Path::new(name)), "{name} is not an artifact");
+        }
+        for name in [
+            "photo.jpg",
+            "thumb.jpg",
+            "photo.thumbnail.jpg",
+            "backup.png",
+        ] {
+            assert!(!is_artifact(Path::new(name)), "{name} is an artifact");
+        }
+    }
+
+    #[test]
+    fn group_by_directory_keeps_order() {
+        let files: Vec<MediaIndex> = ["b/1.jpg", "a/2.jpg", "b/3.jpg", "a/4.jpg", "b/5.jpg"]
+            .iter()
+            .map(|path| MediaIndex {
+                path: PathBuf::from(path),
+                root: PathBuf::new(),
+                media_type: MediaType::Image,
+                format: "jpg".to_string(),
+                flat_stem: None,
+                options: None,
+            })
+            .collect();
+        let chunks = group_by_directory(files, 2);
+        let chunks: Vec<Vec<&str>> = chunks
+            .iter()
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .map(|file| file.path.to_str().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                vec!["b/1.jpg", "b/5.jpg"],
+                vec!["b/3.jpg"],
+                vec!["a/2.jpg"],
+                vec!["a/4.jpg"]
+            ]
+        );
+    }
+
+    #[test]
+    fn replace_original_in_place() {
+        let dir = test_dir("replace");
+        let (source_path, partial_path) = write_original(&dir);
+        replace_original(
+            &source_path,
+            &partial_path,
+            &source_path,
+            &dir,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&source_path).unwrap(), "compressed");
+        assert!(!partial_path.exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_original_keeps_backup() {
+        let dir = test_dir("backup");
+        let (source_path, partial_path) = write_original(&dir);
+        let options = Options {
+            keep_files: true,
+            ..Default::default()
+        };
+        replace_original(&source_path, &partial_path, &source_path, &dir, &options).unwrap();
+        assert_eq!(fs::read_to_string(&source_path).unwrap(), "compressed");
+        let backup_path = dir.join("photo.jpg.backup");
+        assert_eq!(fs::read_to_string(backup_path).unwrap(), "original");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_original_mirrors_backup_dir() {
+        let dir = test_dir("backup-dir");
+        let (root, backup_dir) = (dir.join("photos"), dir.join("backups"));
+        fs::create_dir_all(root.join("2024")).unwrap();
+        let (source_path, partial_path) = write_original(&root.join("2024"));
+        let options = Options {
+            keep_files: true,
+            backup_dir: Some(backup_dir.clone()),
+            ..Default::default()
+        };
+        replace_original(&source_path, &partial_path, &source_path, &root, &options).unwrap();
+        assert_eq!(fs::read_to_string(&source_path).unwrap(), "compressed");
+        let backup_path = backup_dir.join("2024").join("photo.jpg");
+        assert_eq!(fs::read_to_string(backup_path).unwrap(), "original");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replace_original_restores_backup_on_failure() {
+        let dir = test_dir("restore");
+        let (source_path, partial_path) = write_original(&dir);
+        // The output can't be moved into place once it is gone
+        fs::remove_file(&partial_path).unwrap();
+        let options = Options {
+            keep_files: true,
+            ..Default::default()
+        };
+        let result = replace_original(&source_path, &partial_path, &source_path, &dir, &options);
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&source_path).unwrap(), "original");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn every_extension_can_be_written() {
+        let extensions = default_extensions();
+        let options = Options {
+            image_ext: Some(Vec::new()),
+            ..Default::default()
+        };
+        for (ext, media_type) in extensions.iter() {
+            if *media_type != MediaType::Image {
+                assert!(
+                    ffmpeg_args(ext, &[], None).is_some(),
+                    "Missing FFMPEG settings for '{ext}'"
+                );
+                continue;
+            }
+            let file = MediaIndex {
+                path: PathBuf::from(format!("photo.{ext}")),
+                root: PathBuf::new(),
+                media_type: MediaType::Image,
+                format: ext.to_string(),
+                flat_stem: None,
+                options: None,
+            };
+            // Raw camera images can only be read, ImageMagick writes all other image formats
+            let (_, output_ext) = output_target(&file, &options).unwrap();
+            assert!(
+                !RAW_FORMATS.contains(&output_ext.as_str()),
+                "'{ext}' images are written as raw '{output_ext}'"
+            );
+            assert_eq!(extensions.get(output_ext.as_str()), Some(&MediaType::Image));
+        }
+    }
+
+    #[test]
+    fn classify_by_extension() {
+        assert_eq!(classify(Path::new("photo.jpg")), Some(MediaType::Image));
+        assert_eq!(classify(Path::new("song.flac")), Some(MediaType::Audio));
+        assert_eq!(classify(Path::new("dir/clip.mkv")), Some(MediaType::Video));
+        // Extensions are matched case-insensitively
+        assert_eq!(classify(Path::new("IMG_0001.JPG")), Some(MediaType::Image));
+        assert_eq!(classify(Path::new("notes.txt")), None);
+        assert_eq!(classify(Path::new("photo")), None);
+        // A leading dot is part of the name, not an extension
+        assert_eq!(classify(Path::new(".png")), None);
+    }
+
+    #[test]
+    fn classify_file_lowercases_format() {
+        let file =
+            classify_file(Path::new("/photos/IMG.JPG"), Path::new("/photos"), false).unwrap();
+        assert_eq!(file.media_type, MediaType::Image);
+        assert_eq!(file.format, "jpg");
+        assert_eq!(file.root, Path::new("/photos"));
+        assert!(
+            classify_file(Path::new("/photos/notes.txt"), Path::new("/photos"), false).is_none()
+        );
+    }
+
+    #[test]
+    fn classify_file_detects_content() {
+        let dir = test_dir("detect");
+        let png = dir.join("photo");
+        fs::write(&png, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").unwrap();
+        let file = classify_file(&png, &dir, false).unwrap();
+        assert_eq!(
+            (file.media_type, file.format.as_str()),
+            (MediaType::Image, "png")
+        );
+        // Icons are images, but not a format that is compressed
+        let ico = dir.join("favicon.dat");
+        fs::write(&ico, b"\0\0\x01\0\x01\0\x10\x10").unwrap();
+        assert!(classify_file(&ico, &dir, false).is_none());
+        // Raw images keep their format instead of the TIFF they're based on
+        let nef = dir.join("photo.NEF");
+        fs::write(&nef, b"II*\0\x08\0\0\0\0\0\0\0").unwrap();
+        assert_eq!(classify_file(&nef, &dir, true).unwrap().format, "nef");
+        // Backups of earlier runs are never indexed
+        let backup = dir.join("photo.png.backup");
+        fs::copy(&png, &backup).unwrap();
+        assert!(classify_file(&backup, &dir, true).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+//! Safe wrappers around the ImageMagick functions that magick_rust doesn't wrap
+//!
+//! The raw bindings are only called in this module. Every wrapper takes a [`MagickWand`], which
+//! owns a valid wand pointer for as long as it's borrowed, and copies any memory ImageMagick
+//! returns before releasing it.
+use magick_rust::{bindings, MagickWand};
+use std::{
+    ffi::{c_char, c_void, CStr, CString},
+    slice,
+    time::Instant,
+};
+
+/// Amount of images in the wand, e.g. the frames of an animation
+pub fn number_images(wand: &MagickWand) -> usize {
+    // SAFETY: the wand pointer is valid while the wand is borrowed
+    unsafe { bindings::MagickGetNumberImages(wand.wand) }
+}
+
+/// Makes the first image current, the next [`next_image`] returns it again
+pub fn reset_iterator(wand: &MagickWand) {
+    // SAFETY: the wand pointer is valid while the wand is borrowed
+    unsafe { bindings::MagickResetIterator(wand.wand) }
+}
+
+/// Moves to the next image, returns false once there are no more images
+pub fn next_image(wand: &MagickWand) -> bool {
+    // SAFETY: the wand pointer is valid while the wand is borrowed
+    let result = unsafe { bindings::MagickNextImage(wand.wand) };
+    result == bindings::MagickBooleanType_MagickTrue
+}
+
+/// Names of the delegate libraries ImageMagick was built with, e.g. heic or raw
+pub fn delegates() -> Vec<String> {
+    // SAFETY: the delegate list is a static string owned by ImageMagick, or null
+    let delegates = unsafe { bindings::GetMagickDelegates() };
+    if delegates.is_null() {
+        return Vec::new();
+    }
+    // SAFETY: checked for null above, the string is NUL terminated
+    let delegates = unsafe { CStr::from_ptr(delegates) }.to_string_lossy();
+    delegates.split_whitespace().map(str::to_string).collect()
+}
+
+/// Aborts ImageMagick operations on the wand once the deadline has passed
+///
+/// # Safety
+///
+/// The deadline is read by every operation on the wand, so it must outlive the wand.
+pub unsafe fn set_deadline(wand: &MagickWand, deadline: &Instant) {
+    unsafe extern "C" fn monitor(
+        _text: *const c_char,
+        _offset: bindings::MagickOffsetType,
+        _extent: bindings::MagickSizeType,
+        client_data: *mut c_void,
+    ) -> bindings::MagickBooleanType {
+        // SAFETY: the client data is the deadline, which the caller keeps alive
+        let deadline = &*(client_data as *const Instant);
+        if Instant::now() > *deadline {
+            bindings::MagickBooleanType_MagickFalse
+        } else {
+            bindings::MagickBooleanType_MagickTrue
+        }
+    }
+    // SAFETY: the wand pointer is valid while the wand is borrowed, the monitor only reads the
+    // deadline, which the caller keeps alive
+    bindings::MagickSetProgressMonitor(
+        wand.wand,
+        Some(monitor),
+        deadline as *const Instant as *mut c_void,
+    );
+}
+
+/// Copy of the images with the full frame composed at every image, `None` if it failed
+pub fn coalesce_images(wand: &MagickWand) -> Option<MagickWand> {
+    // SAFETY: the wand pointer is valid while the wand is borrowed, the new wand is owned by the
+    // returned MagickWand, which destroys it
+    let coalesced = unsafe { bindings::MagickCoalesceImages(wand.wand) };
+    (!coalesced.is_null()).then(|| MagickWand::new_from_wand(coalesced))
+}
+
+/// Copy of coalesced images where every image only stores the pixels that differ from the previous
+/// one, `None` if it failed
+pub fn optimize_image_layers(wand: &MagickWand) -> Option<MagickWand> {
+    // SAFETY: the wand pointer is valid while the wand is borrowed, the new wand is owned by the
+    // returned MagickWand, which destroys it
+    let optimized = unsafe { bindings::MagickOptimizeImageLayers(wand.wand) };
+    (!optimized.is_null()).then(|| MagickWand::new_from_wand(optimized))
+}
+
+/// Makes the pixels that don't change between images transparent, returns whether it succeeded
+pub fn optimize_image_transparency(wand: &MagickWand) -> bool {
+    // SAFETY: the wand pointer is valid while the wand is borrowed
+    let result = unsafe { bindings::MagickOptimizeImageTransparency(wand.wand) };
+    result == bindings::MagickBooleanType_MagickTrue
+}
+
+/// Resizes the current image and strips its profiles, returns whether it succeeded
+pub fn thumbnail(wand: &MagickWand, columns: usize, rows: usize) -> bool {
+    // SAFETY: the wand pointer is valid while the wand is borrowed
+    let result = unsafe { bindings::MagickThumbnailImage(wand.wand, columns, rows) };
+    result == bindings::MagickBooleanType_MagickTrue
+}
+
+/// Named profile of the current image, e.g. exif, xmp or icc
+pub fn image_profile(wand: &MagickWand, name: &str) -> Option<Vec<u8>> {
+    let c_name = CString::new(name).ok()?;
+    let mut length = 0;
+    // SAFETY: the wand pointer is valid while the wand is borrowed, the name is NUL terminated
+    let data = unsafe { bindings::MagickGetImageProfile(wand.wand, c_name.as_ptr(), &mut length) };
+    if data.is_null() {
+        return None;
+    }
+    // SAFETY: ImageMagick returns a buffer of `length` bytes, which is copied before it's released
+    let profile = unsafe { slice::from_raw_parts(data, length) }.to_vec();
+    // SAFETY: the buffer was allocated by ImageMagick and isn't used after this
+    unsafe { bindings::MagickRelinquishMemory(data as *mut c_void) };
+    Some(profile)
+}
+
+/// Sets a named profile of the current image, returns whether it succeeded
+pub fn set_image_profile(wand: &MagickWand, name: &str, profile: &[u8]) -> bool {
+    let Ok(c_name) = CString::new(name) else {
+        return false;
+    };
+    // SAFETY: the wand pointer is valid while the wand is borrowed, ImageMagick copies the profile
+    let result = unsafe {
+        bindings::MagickSetImageProfile(
+            wand.wand,
+            c_name.as_ptr(),
+            profile.as_ptr() as *const c_void,
+            profile.len(),
+        )
+    };
+    result == bindings::MagickBooleanType_MagickTrue
+}
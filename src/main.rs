@@ -1,345 +1,509 @@
+mod bench;
+mod cli;
+mod progress;
+
 use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use magick_rust::{magick_wand_genesis, MagickWand};
+use cli::{Action, Args, ProgressFormat};
+use comva::*;
+use globset::{GlobBuilder, GlobSetBuilder};
+use indicatif::{MultiProgress, ProgressDrawTarget};
+use indicatif_log_bridge::LogWrapper;
+use log::{info, warn, Level, LevelFilter};
+use progress::{Events, Progress};
 use std::{
-    collections::HashMap,
+    collections::BTreeMap,
     fs,
+    io::{self, IsTerminal, Write},
     path::PathBuf,
-    process::{Command, Stdio},
-    sync::{mpsc, Arc, Mutex, Once},
-    thread,
+    process,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
 
-#[derive(Parser, Debug, Clone)]
-#[clap(author, version, about, long_about = None)]
-struct Options {
-    /// Compress image image files
-    #[clap(short)]
-    image_ext: Option<Option<String>>,
-
-    /// Compress audio files
-    #[clap(short)]
-    audio_ext: Option<Option<String>>,
-
-    /// Compress video files
-    #[clap(short)]
-    video_ext: Option<Option<String>>,
-
-    /// Keep the original files, if original file is overwritten backup files are kept
-    #[clap(short, long)]
-    keep_files: bool,
-
-    /// Image compression quality
-    #[clap(short, long)]
-    quality: Option<u16>,
-
-    /// The amount of worker threads
-    #[clap(short, long, default_value_t = 8)]
-    threads: u64,
-}
-
-/// Type category of media
-#[derive(Clone, Debug, Hash, Ord, PartialOrd, Eq, PartialEq)]
-pub enum MediaType {
-    Image,
-    Audio,
-    Video,
-}
-
-// ImageQuick initialization
-static IM_START: Once = Once::new();
+// Stopped when Ctrl-C is pressed, aborted when it is pressed twice
+static CANCEL: Cancellation = Cancellation::new();
 
 fn main() -> Result<()> {
-    let extensions: HashMap<&str, MediaType> = HashMap::from([
-        ("gif", MediaType::Image),
-        ("jpg", MediaType::Image),
-        ("jpeg", MediaType::Image),
-        ("png", MediaType::Image),
-        ("bmp", MediaType::Image),
-        ("webp", MediaType::Image),
-        ("avif", MediaType::Image),
-        ("mp4", MediaType::Video),
-        ("avi", MediaType::Video),
-        ("mov", MediaType::Video),
-        ("flv", MediaType::Video),
-        ("avi", MediaType::Video),
-        ("mkv", MediaType::Video),
-        ("mp3", MediaType::Audio),
-        ("wav", MediaType::Audio),
-        ("ogg", MediaType::Audio),
-        ("flac", MediaType::Audio),
-        ("opus", MediaType::Audio),
-        ("m4a", MediaType::Audio),
-        ("webm", MediaType::Audio),
-    ]);
-
-    let options = Options::parse();
-
-    let dir = PathBuf::from("./")
-        .canonicalize()
-        .with_context(|| "Failed path canonicalization.")?;
-    let index = index(&dir, extensions)?;
-
-    IM_START.call_once(|| {
-        magick_wand_genesis();
-    });
-    compress(index, &options)?;
-
-    print!("Operation completed.");
+    let extensions = default_extensions();
+    check_extensions(&extensions)?;
 
-    Ok(())
-}
-
-#[derive(Debug)]
-pub struct MediaIndex {
-    pub path: PathBuf,
-    pub media_type: MediaType,
-}
-
-pub fn index(directory: &PathBuf, extensions: HashMap<&str, MediaType>) -> Result<Vec<MediaIndex>> {
-    let mut index_items =
-        index_files(directory, 0, &extensions).with_context(|| "Failed to index files")?;
-    index_items.sort_by(|a, b| a.media_type.cmp(&b.media_type));
-    Ok(index_items)
-}
-
-fn index_files(
-    directory: &PathBuf,
-    depth: u32,
-    extensions: &HashMap<&str, MediaType>,
-) -> Result<Vec<MediaIndex>> {
-    let mut index = Vec::new();
-    for file in fs::read_dir(directory)? {
-        let path = file?.path();
-        if path.is_file() {
-            if let Some(ext) = path.extension() {
-                let ext_str = ext.to_string_lossy().to_string().to_lowercase();
-                if let Some(media_type) = extensions.get(&ext_str as &str) {
-                    index.push(MediaIndex {
-                        path: path.clone(),
-                        media_type: media_type.clone(),
-                    });
-                }
+    let mut args = Args::parse();
+    let multi = init_logging(&args)?;
+    if let Some(Action::Bench(bench_options)) = &args.command {
+        return bench::run(bench_options, &multi);
+    }
+    let mut options = args.options();
+    for config_path in Config::paths() {
+        if config_path.is_file() {
+            options.apply_config(Config::load(&config_path)?);
+        }
+    }
+    // Applied last, so everything else takes precedence
+    if let Some(preset) = options.preset {
+        options.apply_config(preset.config());
+    }
+    options.validate()?;
+    let files = std::mem::take(&mut args.files);
+    args.paths.extend(files);
+    if args.paths.is_empty() {
+        args.paths.push(PathBuf::from("./"));
+    }
+    let only: Vec<MediaType> = args
+        .only
+        .iter()
+        .map(|&media_type| media_type.into())
+        .collect();
+    // Selected media types are compressed even without their format flag
+    for media_type in only.iter() {
+        match media_type {
+            MediaType::Image => {
+                options.image_ext.get_or_insert_with(Vec::new);
+            }
+            MediaType::Audio => {
+                options.audio_ext.get_or_insert(None);
+            }
+            MediaType::Video => {
+                options.video_ext.get_or_insert(None);
             }
-        } else if path.is_dir() {
-            let mut child_index = index_files(&path, depth + 1, extensions)?;
-            index.append(&mut child_index);
         }
     }
-    Ok(index)
-}
-
-fn compress(index: Vec<MediaIndex>, options: &Options) -> Result<()> {
-    println!("Starting compression of {} files..", index.len());
-
-    let pool = ThreadPool::new(options.threads as usize);
-    for file in index.iter() {
-        let mt = file.media_type.clone();
-        let path = file.path.clone();
-        let options = options.clone();
-        pool.execute(move || {
-            compress_file(mt, path, options);
-        });
+    check_output_formats(&options, &extensions)?;
+    if options.to_srgb {
+        options.srgb_profile = match options.srgb_profile.take() {
+            Some(path) if !path.is_file() => {
+                return Err(anyhow!("sRGB profile {path:?} does not exist."));
+            }
+            Some(path) => Some(path),
+            None => SRGB_PROFILES
+                .iter()
+                .map(PathBuf::from)
+                .find(|path| path.is_file()),
+        };
+        if options.srgb_profile.is_none() {
+            warn!("No sRGB ICC profile found, images with an embedded color profile are only converted by colorspace. Set one with --srgb-profile.");
+        }
+    }
+    // Originals are never touched when writing to an output directory
+    if options.output_dir.is_some() {
+        options.keep_files = true;
+    }
+    // Outputs and backups are never indexed
+    let mut skip_dirs = Vec::new();
+    for (dir, name) in [
+        (&options.output_dir, "output"),
+        (&options.backup_dir, "backup"),
+    ] {
+        let Some(dir) = dir else {
+            continue;
+        };
+        if !options.dry_run && !args.list {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {name} directory {dir:?}."))?;
+        }
+        skip_dirs.extend(dir.canonicalize().ok());
     }
-    Ok(())
-}
-
-// Basic threadpool from the Rust book
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
-}
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+    let mut exclude = GlobSetBuilder::new();
+    for pattern in options.exclude.iter() {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid exclude pattern '{pattern}'."))?;
+        exclude.add(glob);
+    }
+    let settings = IndexSettings {
+        max_depth: args.max_depth,
+        exclude: exclude.build()?,
+        detect_content: args.detect_content,
+        skip_dirs,
+        follow_symlinks: args.follow_symlinks,
+        ignore_hidden: !args.no_ignore_hidden,
+        since: args.since,
+        // Directory configs don't apply to listed files
+        options: args.files_from.is_none().then(|| Arc::new(options.clone())),
+    };
 
-enum Message {
-    NewJob(Job),
-    Terminate,
-}
+    let mut media_index = Vec::new();
+    if let Some(list_path) = &args.files_from {
+        media_index = index_list(list_path, &settings)?;
+    } else {
+        for path in args.paths.iter() {
+            let path = path
+                .canonicalize()
+                .with_context(|| format!("Failed to find {path:?}."))?;
+            if !path.is_dir() && !path.is_file() {
+                return Err(anyhow!("{path:?} is not a file or directory."));
+            }
+            media_index.append(&mut index(&path, &settings)?);
+        }
+    }
+    if !only.is_empty() {
+        media_index.retain(|file| only.contains(&file.media_type));
+    }
+    sort_index(&mut media_index, options.sort_by);
+    // Assigned before files are left out, so resumed runs use the same names
+    if options.flatten {
+        assign_flat_stems(&mut media_index);
+    }
+    if args.list {
+        list_index(&media_index);
+        return Ok(());
+    }
 
-impl ThreadPool {
-    pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+    let ledger = match &args.state {
+        Some(state_path) => Some(Ledger::open(state_path, options.dry_run)?),
+        None => None,
+    };
+    if let Some(ledger) = &ledger {
+        let total = media_index.len();
+        media_index.retain(|file| !ledger.contains(&file.path));
+        if media_index.len() < total {
+            info!(
+                "Skipping {} files processed in a previous run.",
+                total - media_index.len()
+            );
+        }
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
+    check_lossy_conversions(&media_index, &options);
 
-        for _ in 0..size {
-            workers.push(Worker::new(Arc::clone(&receiver)));
+    if uses_ffmpeg(&media_index, &options) {
+        if let Err(err) = check_ffmpeg() {
+            if !options.dry_run {
+                return Err(err);
+            }
+            warn!("{err:#}");
         }
-
-        ThreadPool { workers, sender }
     }
 
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
+    if args.estimate {
+        let estimates = estimate(&media_index, &options, args.estimate_seconds, &CANCEL)?;
+        print_estimate(&estimates);
+        return Ok(());
+    }
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+    if args.interactive && !options.dry_run && !confirm(&media_index, &options, args.yes)? {
+        println!("Operation aborted.");
+        return Ok(());
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        for _ in &self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+    ctrlc::set_handler(|| {
+        if CANCEL.stop.swap(true, Ordering::SeqCst) {
+            // Pressed twice, the running commands are killed so none are left running after exiting
+            CANCEL.abort.store(true, Ordering::SeqCst);
+            warn!("Aborting running jobs..");
+            return;
+        }
+        warn!("Shutting down cleanly, waiting for running jobs to finish.. Press Ctrl-C again to abort them.");
+    })?;
+
+    init_imagemagick();
+    let is_heif = |file: &MediaIndex| file.format == "heic" || file.format == "heif";
+    if media_index.iter().any(is_heif) && !magick_has_delegate("heic") {
+        warn!("ImageMagick was built without HEIC support, skipping HEIC images. Install libheif and an ImageMagick build with HEIC support to compress them.");
+        media_index.retain(|file| !is_heif(file));
+    }
+    let is_raw = |file: &MediaIndex| RAW_FORMATS.contains(&file.format.as_str());
+    if media_index.iter().any(is_raw) && !magick_supports_raw() {
+        warn!("ImageMagick was built without raw camera image support, skipping raw images. Install an ImageMagick build with libraw support, or dcraw, to convert them.");
+        media_index.retain(|file| !is_raw(file));
+    }
+    let start = Instant::now();
+    let events = Events::new(args.progress_format);
+    let progress = Progress::new(&multi, &media_index, &options, args.progress_format);
+    let results = compress(media_index, &options, &CANCEL, ledger.as_ref(), &|event| {
+        progress.update(&event);
+        events.send(event);
+    })?;
+    events.finish();
+    let failed = print_summary(&results, &args, start.elapsed())?;
+    if CANCEL.abort.load(Ordering::SeqCst) {
+        process::exit(130);
+    }
+    if args.prune_empty_dirs && !options.dry_run && args.files_from.is_none() {
+        let roots: Vec<PathBuf> = args
+            .paths
+            .iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect();
+        let pruned = prune_empty_dirs(&results, &roots, &settings);
+        if pruned > 0 {
+            info!("Removed {pruned} empty directories.");
         }
+    }
 
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
+    let text = args.progress_format == ProgressFormat::Text;
+    if failed > 0 {
+        if text {
+            println!("Operation completed, {failed} files failed.");
         }
+        process::exit(1);
+    }
+    if text {
+        println!("Operation completed.");
     }
-}
 
-struct Worker {
-    thread: Option<thread::JoinHandle<()>>,
+    Ok(())
 }
 
-impl Worker {
-    fn new(receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-
-            match message {
-                Message::NewJob(job) => {
-                    job();
-                }
-                Message::Terminate => {
-                    break;
-                }
-            }
-        });
-
-        Worker {
-            thread: Some(thread),
+/// Writes the report and prints the summary of a run, returns the amount of failed files
+fn print_summary(results: &[FileResult], args: &Args, elapsed: Duration) -> Result<usize> {
+    let mut stats = Stats::default();
+    for outputs in group_by_source(results) {
+        stats.record(outputs);
+    }
+    if let Some(report_path) = &args.report {
+        let report = serde_json::to_string_pretty(results)?;
+        fs::write(report_path, report)
+            .with_context(|| format!("Failed to write report to {report_path:?}"))?;
+    }
+    // The finished event replaces the summary
+    if args.progress_format == ProgressFormat::Jsonl {
+        return Ok(stats.failed);
+    }
+    if args.dry_run {
+        println!(
+            "Would compress {} files, skip {} files.",
+            stats.compressed, stats.skipped
+        );
+    } else {
+        println!(
+            "Compressed {} files, skipped {} files, {} failed.",
+            stats.compressed, stats.skipped, stats.failed
+        );
+    }
+    if stats.too_small > 0 {
+        println!("Skipped {} files below the minimum size.", stats.too_small);
+    }
+    if !args.dry_run && stats.compressed > 0 {
+        println!(
+            "Saved {} ({:.1}%) across {} files",
+            format_saved(stats.original_size, stats.output_size),
+            (1.0 - stats.output_size as f64 / stats.original_size as f64) * 100.0,
+            stats.compressed
+        );
+    }
+    if let (Some(top), false) = (args.top, args.dry_run) {
+        print_top(results, top);
+    }
+    if !args.dry_run {
+        println!("Finished in {elapsed:.1?}");
+        for (media_type, (files, duration)) in stats.timings.iter() {
+            println!(
+                "  {media_type:?}: {files} files in {:.1?}, {:.2?} per file",
+                Duration::from_secs_f64(*duration),
+                Duration::from_secs_f64(duration / *files as f64)
+            );
         }
     }
+    Ok(stats.failed)
 }
 
-fn compress_file(media_type: MediaType, source_path: PathBuf, options: Options) {
-    let format_flag = match media_type {
-        MediaType::Image => options.image_ext.clone(),
-        MediaType::Audio => options.audio_ext.clone(),
-        MediaType::Video => options.video_ext.clone(),
-    };
-    if let Some(ext_option) = format_flag {
-        let output_ext = ext_option.unwrap_or({
-            source_path
-                .extension()
-                .unwrap()
-                .to_string_lossy()
-                .to_string()
-                .to_lowercase()
-        });
-        let output_path = source_path.with_extension(&output_ext);
+/// Prints the estimated output size of every sampled video and the projected savings
+fn print_estimate(estimates: &[Estimate]) {
+    if estimates.is_empty() {
+        println!("No videos to estimate.");
+        return;
+    }
+    for estimate in estimates {
+        println!(
+            "  {:>10} -> ~{:>10} {:?}",
+            format_size(estimate.original_size),
+            format_size(estimate.estimated_size),
+            estimate.path
+        );
+    }
+    let original_size: u64 = estimates
+        .iter()
+        .map(|estimate| estimate.original_size)
+        .sum();
+    // Originals are kept when the output would be larger
+    let estimated_size: u64 = estimates
+        .iter()
+        .map(|estimate| estimate.estimated_size.min(estimate.original_size))
+        .sum();
+    println!(
+        "Estimated to save {} ({:.1}%) across {} videos",
+        format_saved(original_size, estimated_size),
+        (1.0 - estimated_size as f64 / original_size as f64) * 100.0,
+        estimates.len()
+    );
+}
 
-        let mut input_path = source_path.clone();
-        let mut overwritten = false;
-        if source_path == output_path {
-            let source_ext = source_path
-                .extension()
-                .unwrap()
-                .to_string_lossy()
-                .to_string();
-            input_path.set_extension(source_ext + ".tmp");
-            fs::rename(&source_path, &input_path).expect("Failed to rename input path.");
-            overwritten = true;
-        }
+/// Prints the files with the largest size change in either direction
+fn print_top(results: &[FileResult], top: usize) {
+    let mut changes: Vec<(&FileResult, (u64, u64), i64)> = group_by_source(results)
+        .filter_map(|outputs| {
+            let (original_size, output_size) = source_sizes(outputs)?;
+            let change = original_size as i64 - output_size as i64;
+            Some((&outputs[0], (original_size, output_size), change))
+        })
+        .collect();
+    changes.sort_by_key(|(_, _, change)| -change);
+    let print = |(result, (original_size, output_size), _): &(&FileResult, (u64, u64), i64)| {
+        let (original_size, output_size) = (*original_size, *output_size);
+        println!(
+            "  {:>10} ({:.1}%) {:?}",
+            format_saved(original_size, output_size),
+            (1.0 - output_size as f64 / original_size as f64) * 100.0,
+            result.source_path
+        );
+    };
 
-        if output_path.exists() {
-            println!("Skiped {source_path:?}, output already exists!");
-            return;
-        }
+    let saved: Vec<_> = changes
+        .iter()
+        .filter(|(_, _, change)| *change > 0)
+        .take(top)
+        .collect();
+    if !saved.is_empty() {
+        println!("Largest savings:");
+        saved.into_iter().for_each(print);
+    }
+    let grew: Vec<_> = changes
+        .iter()
+        .rev()
+        .filter(|(_, _, change)| *change < 0)
+        .take(top)
+        .collect();
+    if !grew.is_empty() {
+        println!("Files that grew:");
+        grew.into_iter().for_each(print);
+    }
+}
 
-        println!("Compressing {output_path:?}..");
+/// Compression statistics, a file with several outputs counts once
+#[derive(Debug, Default)]
+struct Stats {
+    compressed: usize,
+    skipped: usize,
+    too_small: usize,
+    failed: usize,
+    original_size: u64,
+    output_size: u64,
+    /// Amount of processed files and their total processing time per media type
+    timings: BTreeMap<MediaType, (usize, f64)>,
+}
 
-        let result = match media_type {
-            MediaType::Image => compress_image(&input_path, &output_path, &options),
-            MediaType::Audio | MediaType::Video => {
-                compress_ffmpeg(&input_path, &output_path, &output_ext)
-            }
-        };
-        match result {
-            Ok(_) => {
-                if !options.keep_files {
-                    fs::remove_file(&input_path).expect("Failed to remove file");
-                } else if overwritten {
-                    let backup_path = input_path.with_extension("backup");
-                    fs::rename(&input_path, &backup_path)
-                        .expect("Failed to rename input to backup.");
-                }
+impl Stats {
+    /// Records the results of all outputs of a file
+    fn record(&mut self, outputs: &[FileResult]) {
+        let has = |status| outputs.iter().any(|result| result.status == status);
+        // A file counts as compressed if any of its outputs is, unless an output failed
+        let status = [
+            FileOutcome::Failed,
+            FileOutcome::Compressed,
+            FileOutcome::Planned,
+            FileOutcome::Kept,
+            FileOutcome::Skipped,
+        ]
+        .into_iter()
+        .find(|&status| has(status))
+        .unwrap_or(FileOutcome::TooSmall);
+        let processed = matches!(
+            status,
+            FileOutcome::Compressed | FileOutcome::Kept | FileOutcome::Failed
+        );
+        // The outputs of a file are written together, so they share the duration
+        if let (Some(duration), true) = (outputs[0].duration, processed) {
+            let timing = self
+                .timings
+                .entry(outputs[0].media_type.clone())
+                .or_default();
+            timing.0 += 1;
+            timing.1 += duration;
+        }
+        match status {
+            FileOutcome::Compressed => {
+                self.compressed += 1;
+                let (original_size, output_size) = source_sizes(outputs).unwrap_or_default();
+                self.original_size += original_size;
+                self.output_size += output_size;
             }
-            Err(err) => eprintln!("Compression of {input_path:?} failed:\n{err}"),
+            FileOutcome::Planned => self.compressed += 1,
+            FileOutcome::Skipped | FileOutcome::Kept => self.skipped += 1,
+            FileOutcome::TooSmall => self.too_small += 1,
+            FileOutcome::Failed => self.failed += 1,
         }
     }
 }
 
-fn compress_image(input_path: &PathBuf, output_path: &PathBuf, options: &Options) -> Result<()> {
-    let input_str = input_path.to_string_lossy().to_string();
-    let output_str = output_path.to_string_lossy().to_string();
-
-    let mut wand = MagickWand::new();
-
-    if let Some(quality) = options.quality {
-        wand.set_compression_quality(quality as usize)
-            .map_err(|_| anyhow!("Failed to set compression quality."))?;
+/// Formats the difference between two sizes, negative if the output is larger
+fn format_saved(original_size: u64, output_size: u64) -> String {
+    if output_size > original_size {
+        format!("-{}", format_size(output_size - original_size))
+    } else {
+        format_size(original_size - output_size)
     }
-
-    wand.read_image(&input_str)
-        .map_err(|_| anyhow!("Failed to read image."))?;
-    wand.write_image(&output_str)
-        .map_err(|_| anyhow!("Failed to write image."))?;
-    Ok(())
 }
 
-fn compress_ffmpeg(input_path: &PathBuf, output_path: &PathBuf, output_ext: &str) -> Result<()> {
-    let input_str = input_path.to_string_lossy().to_string();
-    let output_str = output_path.to_string_lossy().to_string();
+/// Sets up logging, messages are printed above the progress bars
+fn init_logging(args: &Args) -> Result<MultiProgress> {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    // Dependencies only log warnings and errors
+    let logger = env_logger::Builder::new()
+        .filter_level(level.min(LevelFilter::Warn))
+        .filter_module(env!("CARGO_PKG_NAME"), level)
+        .parse_default_env()
+        .format(|buf, record| match record.level() {
+            Level::Info => writeln!(buf, "{}", record.args()),
+            level => writeln!(buf, "{level}: {}", record.args()),
+        })
+        .build();
+    let max_level = logger.filter();
+    let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stdout());
+    LogWrapper::new(multi.clone(), logger).try_init()?;
+    log::set_max_level(max_level);
+    Ok(multi)
+}
 
-    // FFMPEG Settings
-    // TODO: Find better settings
-    let mut ffmpeg_settings = HashMap::from([
-        // Audio Lossy
-        ("mp3", vec!["-qscale:a", "2"]), // See: https://trac.ffmpeg.org/wiki/Encode/MP3
-        // Audio Loseless
-        ("flac", vec!["-compression_level", "12"]), // Max FLAC compression
-        // Video Lossy
-        // Use H.265 encoding with CRF 28
-        // TODO: -r flag to limit framerate
-        // TODO: Down scaling
-        ("mp4", vec!["-vcodec", "libx265", "-crf", "28"]),
-        ("mkv", vec!["-vcodec", "libx265", "-crf", "28"]),
-        ("mov", vec!["-vcodec", "libx265", "-crf", "28"]),
-        ("avi", vec!["-vcodec", "libx265", "-crf", "28"]),
-    ]);
+/// Prints the index grouped by media type, expects the index to be sorted by media type
+fn list_index(media_index: &[MediaIndex]) {
+    for group in media_index.chunk_by(|a, b| a.media_type == b.media_type) {
+        println!("{:?} ({} files):", group[0].media_type, group.len());
+        for file in group {
+            println!("  {:?}", file.path);
+        }
+    }
+    println!("Found {} files.", media_index.len());
+}
 
-    let mut args = Vec::<&str>::new();
-    if let Some(ffmpeg_args) = ffmpeg_settings.get_mut(&output_ext) {
-        args.append(ffmpeg_args);
+/// Asks for confirmation before originals are overwritten or removed
+fn confirm(index: &[MediaIndex], options: &Options, yes: bool) -> Result<bool> {
+    let (mut overwritten, mut removed) = (0, 0);
+    for file in index.iter() {
+        let targets = output_targets(file, options);
+        if targets
+            .iter()
+            .any(|(output_path, _)| is_same_path(&file.path, output_path))
+        {
+            overwritten += 1;
+        } else if !targets.is_empty()
+            && !options.keep_files
+            && targets.iter().all(|(output_path, _)| !output_path.exists())
+        {
+            removed += 1;
+        }
+    }
+    if overwritten + removed == 0 || yes {
+        return Ok(true);
     }
 
-    let output = Command::new("ffmpeg")
-        .arg("-i")
-        .arg(&input_str)
-        .arg(&output_str)
-        .args(args)
-        .arg("-y") // Overwrite
-        .stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .output()
-        .with_context(|| "Failed to run command")?;
-    if !output.status.success() {
-        let stdout_str = String::from_utf8_lossy(&output.stdout);
-        let stderr_str = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!(format!(
-            "Failed FFMPEG execution!\nStdErr: {stderr_str}\nStdOut: {stdout_str}"
-        )));
+    // Asked on stderr, stdout may be JSONL progress events
+    eprintln!("{overwritten} files will be overwritten and {removed} original files removed.");
+    if !io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Unable to ask for confirmation, stdin is not a terminal. Use --yes to continue."
+        ));
     }
-    Ok(())
+    eprint!("Continue? [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
 }
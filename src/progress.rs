@@ -0,0 +1,206 @@
+//! Progress output of a compression run, progress bars for humans or JSON Lines events for frontends
+
+use crate::cli::ProgressFormat;
+use comva::{output_target, MediaIndex, Options, ProgressEvent};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Prints progress events on a separate thread, so lines of different workers don't interleave
+pub struct Events {
+    printer: Option<(Sender<ProgressEvent>, thread::JoinHandle<()>)>,
+}
+
+impl Events {
+    pub fn new(format: ProgressFormat) -> Self {
+        if format != ProgressFormat::Jsonl {
+            return Events { printer: None };
+        }
+        let (sender, receiver) = mpsc::channel::<ProgressEvent>();
+        let printer = thread::spawn(move || {
+            let mut stdout = io::stdout().lock();
+            for event in receiver {
+                let line = serde_json::to_string(&event).expect("Events are serializable");
+                // Flushed per line so frontends receive events immediately
+                if writeln!(stdout, "{line}")
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+                {
+                    // The reader is gone, ignore the remaining events
+                    break;
+                }
+            }
+        });
+        Events {
+            printer: Some((sender, printer)),
+        }
+    }
+
+    pub fn send(&self, event: ProgressEvent) {
+        if let Some((sender, _)) = &self.printer {
+            let _ = sender.send(event);
+        }
+    }
+
+    /// Waits until all events are printed
+    pub fn finish(self) {
+        if let Some((sender, printer)) = self.printer {
+            drop(sender);
+            let _ = printer.join();
+        }
+    }
+}
+
+/// Progress bars, only drawn when stdout is a terminal
+pub struct Progress {
+    bars: Option<(MultiProgress, ProgressBar)>,
+    /// Spinners of the files being processed, with the size of the file and the amount of outputs
+    /// that are not finished yet
+    files: Mutex<HashMap<PathBuf, (ProgressBar, u64, usize)>>,
+    eta: Mutex<Eta>,
+}
+
+impl Progress {
+    pub fn new(
+        multi: &MultiProgress,
+        index: &[MediaIndex],
+        options: &Options,
+        format: ProgressFormat,
+    ) -> Self {
+        let files = Mutex::new(HashMap::new());
+        // Bars would mix with the events on stdout
+        if !io::stdout().is_terminal() || format == ProgressFormat::Jsonl {
+            return Progress {
+                bars: None,
+                files,
+                eta: Mutex::new(Eta::new(0)),
+            };
+        }
+        // Files of media types that are not compressed have no events
+        let compressed: Vec<&MediaIndex> = index
+            .iter()
+            .filter(|file| {
+                output_target(file, file.options.as_deref().unwrap_or(options)).is_some()
+            })
+            .collect();
+        let total_size = compressed.iter().map(|file| file_size(&file.path)).sum();
+        let total_bar = multi.add(ProgressBar::new(compressed.len() as u64));
+        total_bar.set_style(
+            ProgressStyle::with_template("[{elapsed_precise}] {wide_bar} {pos}/{len} files {msg}")
+                .unwrap(),
+        );
+        Progress {
+            bars: Some((multi.clone(), total_bar)),
+            files,
+            eta: Mutex::new(Eta::new(total_size)),
+        }
+    }
+
+    /// Shows a spinner for every file that is being processed, a file is done once all of its
+    /// outputs are
+    pub fn update(&self, event: &ProgressEvent) {
+        let Some((multi, total_bar)) = &self.bars else {
+            return;
+        };
+        let mut files = self.files.lock().unwrap_or_else(|err| err.into_inner());
+        match event {
+            ProgressEvent::FileBegin { path, .. } => {
+                let (_, _, pending) = files.entry(path.clone()).or_insert_with(|| {
+                    let spinner = multi.add(ProgressBar::new_spinner());
+                    spinner.set_message(path.to_string_lossy().to_string());
+                    spinner.enable_steady_tick(Duration::from_millis(100));
+                    (spinner, file_size(path), 0)
+                });
+                *pending += 1;
+            }
+            ProgressEvent::FileDone { path, .. } | ProgressEvent::FileFailed { path, .. } => {
+                let Some((_, _, pending)) = files.get_mut(path) else {
+                    return;
+                };
+                *pending -= 1;
+                if *pending > 0 {
+                    return;
+                }
+                let Some((spinner, size, _)) = files.remove(path) else {
+                    return;
+                };
+                spinner.finish_and_clear();
+                multi.remove(&spinner);
+                total_bar.inc(1);
+                let remaining = self
+                    .eta
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .finish(size);
+                if let Some(remaining) = remaining {
+                    total_bar.set_message(format!("~{} remaining", format_remaining(remaining)));
+                }
+            }
+            ProgressEvent::Finished { .. } => total_bar.finish_and_clear(),
+            ProgressEvent::Started { .. } => {}
+        }
+    }
+}
+
+/// Estimates the remaining time from the amount of bytes completed recently, so that large videos
+/// weigh more than small images
+struct Eta {
+    total_size: u64,
+    completed_size: u64,
+    /// Completion times and the completed size at that time, of the last completed files
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Eta {
+    /// Amount of completed files the rate is averaged over
+    const WINDOW: usize = 20;
+
+    fn new(total_size: u64) -> Self {
+        Eta {
+            total_size,
+            completed_size: 0,
+            samples: VecDeque::from([(Instant::now(), 0)]),
+        }
+    }
+
+    /// Records a completed file, returns the remaining time once the rate is known
+    fn finish(&mut self, size: u64) -> Option<Duration> {
+        self.completed_size += size;
+        self.samples
+            .push_back((Instant::now(), self.completed_size));
+        if self.samples.len() > Self::WINDOW + 1 {
+            self.samples.pop_front();
+        }
+        let ((start, start_size), (end, end_size)) = (self.samples.front()?, self.samples.back()?);
+        let rate = (end_size - start_size) as f64 / end.duration_since(*start).as_secs_f64();
+        if !rate.is_finite() || rate <= 0.0 {
+            return None;
+        }
+        let remaining_size = self.total_size.saturating_sub(self.completed_size);
+        Some(Duration::from_secs_f64(remaining_size as f64 / rate))
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+/// Formats a remaining time roughly, e.g. 12m or 1h 5m
+fn format_remaining(remaining: Duration) -> String {
+    let secs = remaining.as_secs();
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs / 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}